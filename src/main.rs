@@ -12,6 +12,8 @@ use std::str::FromStr;
 use std::error::Error;
 use std::io::prelude::*;
 use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
 use std::fs::OpenOptions;
 use std::collections::HashMap;
 
@@ -21,19 +23,55 @@ use sdl2::rect::Rect;
 use sdl2::event::Event;
 use sdl2::pixels::Color;
 use sdl2::keyboard::Keycode;
+use sdl2::keyboard::LSHIFTMOD;
+use sdl2::keyboard::RSHIFTMOD;
 use sdl2::image::LoadTexture;
 use sdl2::render::TextureQuery;
 use sdl2::render::Renderer;
 use sdl2::render::Texture;
+use sdl2::ttf::Font;
 use sdl2::gfx::primitives::DrawRenderer;
 
 use image::Pixel;
 use image::GenericImage;
 
+// Project settings file with key = value lines
+const CONFIG_FILE: &str = "puzzle.cfg";
+
+// Font for on-screen texts if not set in config
+const DEFAULT_FONT: &str = "/usr/share/fonts/truetype/dejavu/DejaVuSans.ttf";
+
 // SDL window size - puzzle pieces bitmap must fit even with rotation
 const WND_WIDTH: usize = 2000;
 const WND_HEIGHT: usize = 2000;
 
+// Suspect extracted pieces in current dir like puzzle.cfg, one "png_no: problems" line each
+const REVIEW_FILE: &str = "review.txt";
+
+// Keys read from puzzle.cfg, the prefixed ones are followed by png_no or image file name
+const CONFIG_KEYS: [&str; 28] = ["data_dir", "jpg_dir", "solved_file", "font", "window_width",
+                                 "window_height", "jag_min_dst", "jag_width", "jag_height",
+                                 "edge_cut", "scale", "compare_scale", "sheet_width_mm",
+                                 "sheet_height_mm", "lens_k1", "check_tolerance",
+                                 "edge_min_len", "edge_max_len", "index_candidates",
+                                 "candidates_b", "candidates_c", "candidates_d",
+                                 "search_reverse", "top_quads", "block_candidates",
+                                 "block_beam", "auto_max_score", "auto_min_margin"];
+const CONFIG_KEY_PREFIXES: [&str; 5] = ["jag_min_dst.", "jag_width.", "jag_height.",
+                                        "edge_cut.", "scale."];
+
+// Bigger pixels are shrinked before making texture from them
+const MAX_TEXTURE_SIZE: usize = 4096;
+
+// Points closer then this (in pixels) to the other edge are covered by it
+const COVERAGE_DST: isize = 3;
+
+// Added to diff of pairs that must not be used (same edge, rejected, solved to other)
+const SKIP_PENALTY: usize = 100000000;
+
+// Number of stripes in edge descriptor used by candidate index
+const DESCRIPTOR_LEN: usize = 16;
+
 // Color masks used to detect borders etc...
 const RED_MASK_NO_MATERIAL: u8 = 1;
 const RED_MASK_MATERIAL: u8 = 1 << 6;
@@ -54,6 +92,28 @@ struct DisplayPixelState {
     autorotate: bool,
 }
 
+// Texts displayed over pixels
+struct Hud {
+    labels: Vec<(usize, usize, String)>, // x,y in pixels coordinates and text
+    lines: Vec<String>, // status lines in the bottom-left window corner
+    textures: Option<HudTextures>, // rendered texts kept between display_pixels calls
+}
+
+// Rendered HUD, labels and lines are the texts textures were made from
+struct HudTextures {
+    labels: Vec<(usize, usize, String)>,
+    lines: Vec<String>,
+    label_textures: Vec<(usize, usize, Texture)>,
+    line_textures: Vec<Texture>,
+    lines_len: usize, // line textures without typed number
+}
+
+// Outline points of pieces by piece_no
+type Pieces = HashMap<usize, Vec<(usize, usize)>>;
+
+// Quad in solver summary: (score, combi, pairs, sorted edge index pairs to find the same quad)
+type RankedQuad = (usize, usize, String, Vec<(usize, usize)>);
+
 struct EdgeInfo {
     points: Vec<(usize, usize)>,
     edge_no: usize, // e.g. 103 is 10.3.txt
@@ -63,6 +123,69 @@ struct EdgeInfo {
     diff_to: Vec<usize>, // distance sum to edge at given index (in edges vector)
     best_diff: Vec<(usize, usize)>, // top 10 (edge_index, diff)
     solved_index: usize, // for solved edge_index to the other, for unsolved usize::max_value
+    rejected: Vec<usize>, // edge indices marked by user as not fitting
+    candidates: Vec<usize>, // plausible partners from descriptor index, empty to compare all
+    solved_source: String, // manual, auto or imported for solved edge
+}
+
+// Node of KD-tree with edge descriptors
+struct KdNode {
+    edge_index: usize,
+    axis: usize,
+    left: usize, // node index or usize::max_value
+    right: usize,
+}
+
+// Length independent similarity of two edges
+#[derive(Copy, Clone)]
+struct EdgeSimilarity {
+    rms: f64, // root mean square distance between points of both edges in pixels
+    coverage_a: f64, // part of edge a points closer then COVERAGE_DST to edge b
+    coverage_b: f64, // and the other way
+}
+
+// Parameters of edges extraction, jag values are divisors of sqr
+#[derive(Copy, Clone, Debug)]
+struct ExtractionParams {
+    jag_min_dst: usize, // border lines closer then sqr / jag_min_dst are one line
+    jag_width: usize, // rows narrower then sqr / jag_width are jags
+    jag_height: usize, // columns shorter then sqr / jag_height are jags
+    edge_cut: usize, // pixels cut from border in corners to split it to edges
+}
+
+// Piece position in assembled puzzle, x,y are grid cells
+#[derive(Copy, Clone)]
+struct Placement {
+    x: isize,
+    y: isize,
+    rotation: usize, // side on the left, see rotate_piece
+    cluster: usize,
+}
+
+// Piece drawn in solver window, used to find edge clicked by mouse
+struct DrawnPiece {
+    piece_no: usize,
+    rotation: usize, // side drawn on the left, see rotate_piece
+    left: usize,
+    top: usize,
+    points: Vec<(usize, usize)>,
+}
+
+// Edge pairs accepted by one user decision, kept on undo/redo stack
+struct SolvedStep {
+    pairs: Vec<(usize, usize)>, // (edge_index, edge_index)
+    score: usize,
+    source: &'static str, // manual or auto, written to solved_edges.txt
+    timestamp: u64, // seconds since epoch when accepted
+    session: u64, // timestamp of solver start
+    lines: String, // exactly as appended to solved_edges.txt, undo removes them
+}
+
+// Solver settings from command line
+struct SolveOptions {
+    pref_pieces: Vec<usize>, // their edges are proposed first
+    auto_solve: bool, // accept clear matches without asking
+    frontier_mode: bool, // propose quads on border of the biggest cluster
 }
 
 // Near point iterator
@@ -558,52 +681,100 @@ fn find_corners(pixels: &mut Vec<u8>,
     return (best_x, best_y, best_bot_x, best_bot_y);
 }
 
-fn rotate_and_find_corners(renderer: &mut Renderer,
-                           texture: &Texture,
+// Border point nearest to x,y, used to put manually clicked corner on the border
+fn nearest_border(pixels: &Vec<u8>,
+                  sqr: usize,
+                  bounds: URect,
+                  x: usize,
+                  y: usize)
+                  -> (usize, usize) {
+    let mut best = (x, y);
+    let mut best_dst = usize::max_value();
+    for by in bounds.min_y..bounds.max_y {
+        for bx in bounds.min_x..bounds.max_x {
+            if pixels[3 * (sqr * by + bx)] & RED_MASK_BORDER == 0 {
+                continue;
+            }
+            let dx = cmp::max(x, bx) - cmp::min(x, bx);
+            let dy = cmp::max(y, by) - cmp::min(y, by);
+            if dx * dx + dy * dy < best_dst {
+                best = (bx, by);
+                best_dst = dx * dx + dy * dy;
+            }
+        }
+    }
+    best
+}
+
+// Rotate image clockwise by angle degrees around its center, image is placed at
+// shift,shift of white sqr x sqr pixels. It's what renderer.copy_ex does, but in memory
+// so the image does not have to fit in window. Pixels buffer is reused between calls.
+fn rotate_image(img: &image::RgbImage,
+                angle: f64,
+                shift: usize,
+                sqr: usize,
+                pixels: &mut Vec<u8>) {
+    let (width, height) = img.dimensions();
+    let (sin, cos) = angle.to_radians().sin_cos();
+    let cx = shift as f64 + width as f64 / 2.0;
+    let cy = shift as f64 + height as f64 / 2.0;
+
+    pixels.clear();
+    pixels.resize(3 * sqr * sqr, 255u8);
+    for y in 0..sqr {
+        let dy = y as f64 + 0.5 - cy;
+        for x in 0..sqr {
+            // Inverse rotation of pixel center, see unrotate_point
+            let dx = x as f64 + 0.5 - cx;
+            let src_x = width as f64 / 2.0 + dx * cos + dy * sin;
+            let src_y = height as f64 / 2.0 - dx * sin + dy * cos;
+            if src_x < 0.0 || src_y < 0.0 || src_x >= width as f64 || src_y >= height as f64 {
+                continue;
+            }
+            let pix = img.get_pixel(src_x as u32, src_y as u32).data;
+            let offset = 3 * (sqr * y + x);
+            pixels[offset] = pix[0];
+            pixels[offset + 1] = pix[1];
+            pixels[offset + 2] = pix[2];
+        }
+    }
+}
+
+// Rotated picture is left in pixels, returns top and bottom corner and bounds
+fn rotate_and_find_corners(img: &image::RgbImage,
                            angle: f64,
                            shift: usize,
                            sqr: usize,
-                           width: u32,
-                           height: u32,
-                           draw_corners: bool)
-                           -> (usize, usize, usize, usize, Vec<u8>, URect) {
-
-    renderer.set_draw_color(Color::RGB(255, 255, 255));
-    renderer.fill_rect(Rect::new(0, 0, sqr as u32, sqr as u32)).unwrap();
-
-    renderer.copy_ex(&texture,
-                     None,
-                     Some(Rect::new(shift as i32, shift as i32, width, height)),
-                     angle,
-                     None,
-                     false,
-                     false)
-        .unwrap();
-
-    //renderer.present();
+                           params: &ExtractionParams,
+                           draw_corners: bool,
+                           pixels: &mut Vec<u8>)
+                           -> (usize, usize, usize, usize, URect) {
 
-    let mut pixels = renderer.read_pixels(Some(Rect::new(0, 0, sqr as u32, sqr as u32)),
-                                          PixelFormatEnum::RGB24)
-        .unwrap();
+    rotate_image(img, angle, shift, sqr, pixels);
 
     // Detect material and bounds
-    let bounds = detect_material(&mut pixels, sqr);
+    let bounds = detect_material(pixels, sqr);
 
     // Detect pieces (the biggest pieces of material)
-    detect_piece(&mut pixels, sqr, bounds);
+    detect_piece(pixels, sqr, bounds);
 
     // Detect borders
-    detect_border(&mut pixels, sqr, bounds);
+    detect_border(pixels, sqr, bounds);
 
     // Remove dead end points from border
-    remove_dead_end_border(&mut pixels, sqr, bounds);
+    remove_dead_end_border(pixels, sqr, bounds);
 
     // Find jags that could spoil finding corners
-    detect_jags(&mut pixels, sqr, bounds, sqr / 48, sqr / 6, sqr / 6);
+    detect_jags(pixels,
+                sqr,
+                bounds,
+                sqr / params.jag_min_dst,
+                sqr / params.jag_width,
+                sqr / params.jag_height);
 
-    let rv = find_corners(&mut pixels, sqr, bounds, draw_corners);
+    let rv = find_corners(pixels, sqr, bounds, draw_corners);
 
-    return (rv.0, rv.1, rv.2, rv.3, pixels, bounds);
+    (rv.0, rv.1, rv.2, rv.3, bounds)
 }
 
 fn find_edge(pixels: &mut Vec<u8>,
@@ -612,11 +783,13 @@ fn find_edge(pixels: &mut Vec<u8>,
              top_x: usize,
              top_y: usize,
              bot_x: usize,
-             bot_y: usize)
+             bot_y: usize,
+             edge_cut: usize)
              -> Vec<(usize, usize)> {
 
     // Split border in top and bot points into 2 parts
-    for i in 0..10 {
+    for i in 0..edge_cut {
+        let col = (255 - 250 * i / edge_cut) as u8;
         pixels[3 * (sqr * (top_y - i) + top_x - i)] &= !RED_MASK_BORDER;
         pixels[3 * (sqr * (top_y + i) + top_x + i)] &= !RED_MASK_BORDER;
         pixels[3 * (sqr * (bot_y + i) + bot_x - i)] &= !RED_MASK_BORDER;
@@ -628,15 +801,15 @@ fn find_edge(pixels: &mut Vec<u8>,
         pixels[3 * (sqr * (bot_y + i) + bot_x - i + 1)] &= !RED_MASK_BORDER;
         pixels[3 * (sqr * (bot_y - i) + bot_x + i + 1)] &= !RED_MASK_BORDER;
 
-        pixels[3 * (sqr * (top_y - i) + top_x - i) + 1] = (255 - 25 * i) as u8;
-        pixels[3 * (sqr * (top_y + i) + top_x + i) + 1] = (255 - 25 * i) as u8;
-        pixels[3 * (sqr * (bot_y + i) + bot_x - i) + 1] = (255 - 25 * i) as u8;
-        pixels[3 * (sqr * (bot_y - i) + bot_x + i) + 1] = (255 - 25 * i) as u8;
+        pixels[3 * (sqr * (top_y - i) + top_x - i) + 1] = col;
+        pixels[3 * (sqr * (top_y + i) + top_x + i) + 1] = col;
+        pixels[3 * (sqr * (bot_y + i) + bot_x - i) + 1] = col;
+        pixels[3 * (sqr * (bot_y - i) + bot_x + i) + 1] = col;
 
-        pixels[3 * (sqr * (top_y - i) + top_x - i + 1) + 1] = (255 - 25 * i) as u8;
-        pixels[3 * (sqr * (top_y + i) + top_x + i + 1) + 1] = (255 - 25 * i) as u8;
-        pixels[3 * (sqr * (bot_y + i) + bot_x - i + 1) + 1] = (255 - 25 * i) as u8;
-        pixels[3 * (sqr * (bot_y - i) + bot_x + i + 1) + 1] = (255 - 25 * i) as u8;
+        pixels[3 * (sqr * (top_y - i) + top_x - i + 1) + 1] = col;
+        pixels[3 * (sqr * (top_y + i) + top_x + i + 1) + 1] = col;
+        pixels[3 * (sqr * (bot_y + i) + bot_x - i + 1) + 1] = col;
+        pixels[3 * (sqr * (bot_y - i) + bot_x + i + 1) + 1] = col;
     }
 
     // Fill the edge in the middle of piece height
@@ -671,41 +844,132 @@ enum UserAction {
     NoAction,
     Number(usize),
     Delete,
+    Undo,
+    Redo,
+    Reject(usize),
+    Click(usize, usize),
+    Confirm,
+    Cancel,
+    Assembled,
+    Block(usize),
+}
+
+// Render text into texture, None for empty text
+fn text_texture(renderer: &mut Renderer, font: &Font, text: &str, color: Color) -> Option<Texture> {
+    if text.is_empty() {
+        return None;
+    }
+    let surface = match font.render(text).blended(color) {
+        Ok(surface) => surface,
+        Err(e) => {
+            println!("couldn't render {}: {:?}", text, e);
+            return None;
+        }
+    };
+    renderer.create_texture_from_surface(&surface).ok()
+}
+
+fn draw_hud(renderer: &mut Renderer,
+            sqr: usize,
+            dst_rect: Rect,
+            labels: &Vec<(usize, usize, Texture)>,
+            lines: &Vec<Texture>) {
+
+    // Labels move and zoom with pixels
+    for &(x, y, ref texture) in labels.iter() {
+        let TextureQuery { width, height, .. } = texture.query();
+        let wx = dst_rect.x() + (x as i64 * dst_rect.width() as i64 / sqr as i64) as i32;
+        let wy = dst_rect.y() + (y as i64 * dst_rect.height() as i64 / sqr as i64) as i32;
+        let _ = renderer.copy(texture, None, Some(Rect::new(wx, wy, width, height)));
+    }
+
+    // Lines stay in the bottom-left corner
+    let wnd_height = renderer.window().unwrap().size().1 as i32;
+    let mut y = wnd_height;
+    for texture in lines.iter().rev() {
+        let TextureQuery { width, height, .. } = texture.query();
+        y -= height as i32;
+        let _ = renderer.copy(texture, None, Some(Rect::new(0, y, width, height)));
+    }
 }
 
 fn display_pixels(pixels: &Vec<u8>,
                   sqr: usize,
                   sdl_context: &sdl2::Sdl,
                   renderer: &mut Renderer,
-                  state: &mut DisplayPixelState)
+                  state: &mut DisplayPixelState,
+                  hud: &mut Hud,
+                  font: Option<&Font>)
                   -> UserAction {
 
+    // Too big pixels take every step-th pixel
+    let step = sqr.div_ceil(MAX_TEXTURE_SIZE);
+    let tex_sqr = sqr / step;
     let mut res_texture =
-        renderer.create_texture_streaming(PixelFormatEnum::RGB24, sqr as u32, sqr as u32).unwrap();
+        renderer.create_texture_streaming(PixelFormatEnum::RGB24, tex_sqr as u32, tex_sqr as u32)
+            .unwrap();
 
     // Create texture with result
-    let mut index = 0;
-    res_texture.with_lock(None, |buffer: &mut [u8], pitch: usize| for y in 0..sqr {
-            for x in 0..sqr {
-                let src_offset = y * pitch + x * 3;
+    res_texture.with_lock(None, |buffer: &mut [u8], pitch: usize| for y in 0..tex_sqr {
+            for x in 0..tex_sqr {
+                let src_offset = 3 * (sqr * y * step + x * step);
                 let dst_offset = y * pitch + x * 3;
-                buffer[dst_offset + 0] |= pixels[src_offset];
-                buffer[dst_offset + 1] |= pixels[src_offset + 1];
-                buffer[dst_offset + 2] |= pixels[src_offset + 2];
-                index += 1;
+                buffer[dst_offset] = pixels[src_offset];
+                buffer[dst_offset + 1] = pixels[src_offset + 1];
+                buffer[dst_offset + 2] = pixels[src_offset + 2];
             }
         })
         .unwrap();
 
     let mut event_pump = sdl_context.event_pump().unwrap();
 
-    let mut dst_rect = Rect::new(0, 0, sqr as u32, sqr as u32);
+    // Shrink to window, P zooms back
+    let wnd_size = renderer.window().unwrap().size();
+    let fit = cmp::min(sqr as u32, cmp::min(wnd_size.0, wnd_size.1));
+    let mut dst_rect = Rect::new(0, 0, fit, fit);
 
     let mut num = 0;
 
+    // HUD texts are rendered only when they change, just typed number is added
+    let rendered = match hud.textures {
+        Some(ref textures) => textures.labels == hud.labels && textures.lines == hud.lines,
+        None => false,
+    };
+    if !rendered {
+        let mut label_textures = vec![];
+        let mut line_textures = vec![];
+        if let Some(font) = font {
+            for &(x, y, ref text) in hud.labels.iter() {
+                let color = Color::RGB(255, 255, 255);
+                if let Some(texture) = text_texture(renderer, font, text, color) {
+                    label_textures.push((x, y, texture));
+                }
+            }
+            for line in hud.lines.iter() {
+                if let Some(texture) = text_texture(renderer, font, line, Color::RGB(255, 255, 0)) {
+                    line_textures.push(texture);
+                }
+            }
+        }
+        hud.textures = Some(HudTextures {
+            labels: hud.labels.clone(),
+            lines: hud.lines.clone(),
+            lines_len: line_textures.len(),
+            label_textures,
+            line_textures,
+        });
+    }
+    let textures = hud.textures.as_mut().unwrap();
+    let lines_len = textures.lines_len;
+    let label_textures = &textures.label_textures;
+    let line_textures = &mut textures.line_textures;
+    line_textures.truncate(lines_len);
+    let mut shown_num = 0;
+
     loop {
         renderer.clear();
         renderer.copy(&res_texture, None, Some(dst_rect)).unwrap();
+        draw_hud(renderer, sqr, dst_rect, label_textures, line_textures);
         renderer.present();
         for event in event_pump.poll_iter() {
             match event {
@@ -757,6 +1021,35 @@ fn display_pixels(pixels: &Vec<u8>,
                 Event::KeyDown { keycode: Some(Keycode::C), .. } => {
                     return UserAction::Compute;
                 }
+                Event::MouseButtonDown { x, y, .. } => {
+                    // Window to pixels coordinates
+                    let px = (x - dst_rect.x()) as i64 * sqr as i64 / dst_rect.width() as i64;
+                    let py = (y - dst_rect.y()) as i64 * sqr as i64 / dst_rect.height() as i64;
+                    if px >= 0 && py >= 0 && px < sqr as i64 && py < sqr as i64 {
+                        return UserAction::Click(px as usize, py as usize);
+                    }
+                }
+                Event::KeyDown { keycode: Some(Keycode::Y), .. } => {
+                    return UserAction::Confirm;
+                }
+                Event::KeyDown { keycode: Some(Keycode::N), .. } => {
+                    return UserAction::Cancel;
+                }
+                Event::KeyDown { keycode: Some(Keycode::V), .. } => {
+                    return UserAction::Assembled;
+                }
+                Event::KeyDown { keycode: Some(Keycode::X), .. } => {
+                    return UserAction::Reject(num);
+                }
+                Event::KeyDown { keycode: Some(Keycode::B), .. } => {
+                    return UserAction::Block(num);
+                }
+                Event::KeyDown { keycode: Some(Keycode::U), keymod, .. } => {
+                    if keymod.intersects(LSHIFTMOD | RSHIFTMOD) {
+                        return UserAction::Redo;
+                    }
+                    return UserAction::Undo;
+                }
                 Event::KeyDown { keycode: Some(Keycode::Num0), .. } => {
                     state.autorotate = false;
                     num = num * 10;
@@ -816,6 +1109,17 @@ fn display_pixels(pixels: &Vec<u8>,
                 _ => {}
             }
         }
+        if num != shown_num {
+            shown_num = num;
+            line_textures.truncate(lines_len);
+            if let Some(font) = font {
+                let text = format!("num={}", num);
+                let color = Color::RGB(0, 255, 255);
+                if let Some(texture) = text_texture(renderer, font, &text, color) {
+                    line_textures.push(texture);
+                }
+            }
+        }
         if state.autorotate {
             return UserAction::NoAction;
         }
@@ -856,14 +1160,183 @@ fn save_points(points: &Vec<(usize, usize)>, img_file: &str, filename: &str) {
     }
 }
 
+// Extraction parameters for png, key.png_no (e.g. jag_width.12) in config overrides key
+fn extraction_params(config: &HashMap<String, String>, png_no: usize) -> ExtractionParams {
+    let get = |key: &str, default: usize| -> usize {
+        match config_f64(config, &format!("{}.{}", key, png_no)).or(config_f64(config, key)) {
+            Some(value) => cmp::max(value as usize, 1),
+            None => default,
+        }
+    };
+    ExtractionParams {
+        jag_min_dst: get("jag_min_dst", 48),
+        jag_width: get("jag_width", 6),
+        jag_height: get("jag_height", 6),
+        edge_cut: get("edge_cut", 10),
+    }
+}
+
+// Space around image so that rotation does not crop it and square where the shifted
+// image always fits
+fn rotation_square(width: u32, height: u32) -> (usize, usize) {
+    // Must be multiple of 4 to play well with texture pitch
+    let shift = ((cmp::max(width, height) as usize) / 3 + 5) & !3usize;
+    (shift, 5 * shift) // 1xleft shift, 3/3 texture, 1xright shift
+}
+
+// Sweep rotation like process_png, just with coarse steps and without window, and return
+// angle with the most vertical corners of the side
+fn best_side_angle(img: &image::RgbImage,
+                   side: usize,
+                   shift: usize,
+                   sqr: usize,
+                   params: &ExtractionParams)
+                   -> f64 {
+    let mut best_corner_delta = usize::max_value();
+    let mut best_corner_angle = 0f64;
+    let mut pixels = vec![];
+    let mut r = -5f64;
+    while r <= 9f64 {
+        let angle = (90 * side) as f64 + r;
+        let rv = rotate_and_find_corners(img, angle, shift, sqr, params, false, &mut pixels);
+        let corner_delta = cmp::max(rv.0, rv.2) - cmp::min(rv.0, rv.2);
+        if corner_delta <= best_corner_delta {
+            best_corner_delta = corner_delta;
+            best_corner_angle = angle;
+        }
+        r += if corner_delta > 10 { 1f64 } else { 0.5f64 };
+    }
+    best_corner_angle
+}
+
+// How bad are four extracted edges, 0 for edges of the same length. Lengths are compared
+// as y span because edge is on the left side. Missing or too short edges and edges with
+// more then 3 points per row (border leaked into the piece) are bad.
+fn edges_badness(edges: &Vec<Vec<(usize, usize)>>, sqr: usize) -> f64 {
+    let mut spans = vec![];
+    let mut badness = 0.0;
+    for edge in edges.iter() {
+        let min_y = edge.iter().map(|p| p.1).min().unwrap_or(0);
+        let max_y = edge.iter().map(|p| p.1).max().unwrap_or(0);
+        let span = max_y - min_y;
+        if span < sqr / 20 {
+            badness += 10.0;
+        } else if edge.len() > 3 * span {
+            badness += 1.0;
+        }
+        spans.push(span as f64);
+    }
+    let max = spans.iter().cloned().fold(0.0, f64::max);
+    let min = spans.iter().cloned().fold(f64::MAX, f64::min);
+    let mean = spans.iter().sum::<f64>() / spans.len() as f64;
+    if mean > 0.0 {
+        badness += (max - min) / mean;
+    }
+    badness
+}
+
+// Badness of edges extracted with params, angles are best angles of sides for the jag
+// params (edge_cut does not change them)
+fn extraction_badness(img: &image::RgbImage,
+                      angles: &Vec<f64>,
+                      shift: usize,
+                      sqr: usize,
+                      params: &ExtractionParams,
+                      pixels: &mut Vec<u8>)
+                      -> f64 {
+    let mut edges = vec![];
+    for &angle in angles.iter() {
+        let rv = rotate_and_find_corners(img, angle, shift, sqr, params, false, pixels);
+        edges.push(find_edge(pixels, sqr, rv.4, rv.0, rv.1, rv.2, rv.3, params.edge_cut));
+    }
+    edges_badness(&edges, sqr)
+}
+
+// Try several extraction parameters on png and pick the one giving four clean edges of
+// similar length. Jag min distance and width (with the same height) are tried first,
+// then jag height alone. The best is set in puzzle.cfg for the png.
+fn tune_extraction(img_file: &str,
+                   png_no: usize,
+                   config: &HashMap<String, String>)
+                   -> ExtractionParams {
+    let img = image::open(Path::new(img_file)).unwrap().to_rgb();
+    let (width, height) = img.dimensions();
+    let (shift, sqr) = rotation_square(width, height);
+    let mut pixels = vec![];
+
+    // Current parameters (defaults or tuned before) are kept if others are not better
+    let mut best = extraction_params(config, png_no);
+    let angles: Vec<f64> =
+        (0..4).map(|side| best_side_angle(&img, side, shift, sqr, &best)).collect();
+    let mut best_badness = extraction_badness(&img, &angles, shift, sqr, &best, &mut pixels);
+    println!("{} {:?} badness {:.3}", img_file, best, best_badness);
+
+    let mut jags = vec![];
+    for &jag_min_dst in [48, 32, 64].iter() {
+        for &jag_width in [6, 4, 8, 12].iter() {
+            jags.push((jag_min_dst, jag_width, jag_width));
+        }
+    }
+    for round in 0..2 {
+        if round == 1 {
+            jags = [6, 4, 8, 12].iter().map(|h| (best.jag_min_dst, best.jag_width, *h)).collect();
+        }
+        for &(jag_min_dst, jag_width, jag_height) in jags.iter() {
+            let mut params = ExtractionParams {
+                jag_min_dst,
+                jag_width,
+                jag_height,
+                edge_cut: 10,
+            };
+            let angles: Vec<f64> =
+                (0..4).map(|side| best_side_angle(&img, side, shift, sqr, &params)).collect();
+
+            for &edge_cut in [10, 6, 14].iter() {
+                params.edge_cut = edge_cut;
+                let badness =
+                    extraction_badness(&img, &angles, shift, sqr, &params, &mut pixels);
+                println!("{} {:?} badness {:.3}", img_file, params, badness);
+                if badness < best_badness {
+                    best_badness = badness;
+                    best = params;
+                }
+            }
+        }
+    }
+
+    println!("{} best {:?} badness {:.3}", img_file, best, best_badness);
+    set_config_values(&vec![(format!("jag_min_dst.{}", png_no), best.jag_min_dst.to_string()),
+                            (format!("jag_width.{}", png_no), best.jag_width.to_string()),
+                            (format!("jag_height.{}", png_no), best.jag_height.to_string()),
+                            (format!("edge_cut.{}", png_no), best.edge_cut.to_string())]);
+    best
+}
+
 fn process_png(img_file: &str,
                png_no: usize,
                sdl_context: &sdl2::Sdl,
-               display_state: &mut DisplayPixelState) {
+               display_state: &mut DisplayPixelState,
+               font: Option<&Font>,
+               params: &ExtractionParams,
+               config: &HashMap<String, String>) {
+
+    // Image is processed in memory, window just shows it
+    let img = image::open(Path::new(img_file)).unwrap().to_rgb();
+    let (width, height) = img.dimensions();
+
+    let (shift, sqr) = rotation_square(width, height);
+
+    println!("{} {}x{} shift={} sqr={}",
+             img_file,
+             width,
+             height,
+             shift,
+             sqr);
 
     let video_subsystem = sdl_context.video().unwrap();
 
-    let window = video_subsystem.window(img_file, WND_WIDTH as u32, WND_HEIGHT as u32)
+    let (wnd_width, wnd_height) = viewer_size(&video_subsystem, config, sqr, sqr);
+    let window = video_subsystem.window(img_file, wnd_width as u32, wnd_height as u32)
         .position(200, 0)
         .opengl()
         .build()
@@ -871,136 +1344,555 @@ fn process_png(img_file: &str,
 
     let mut renderer = window.renderer().build().unwrap();
 
-    let texture = renderer.load_texture(img_file).unwrap();
+    // Corners clicked by user replace the found ones
+    let corners_file = format!("{}.corners", img_file);
 
-    let TextureQuery { width, height, .. } = texture.query();
+    // Rotated picture, the same buffer for all angles
+    let mut pixels = vec![];
 
-    // Some space so that rotation does not crop image. Must be multiple of 4
-    // to play well with texture pitch.
-    let shift = ((cmp::max(width, height) as usize) / 3 + 5) & !3usize;
+    // Extract all sides again when corners change
+    'extracting: loop {
 
-    // Squate that the shifted puzzle always fits
-    let sqr = (5 * shift) as usize; // 1xleft shift, 3/3 texture, 1xright shift
+        // Rotation and corners of each side, used to compose colour picture of solved puzzle
+        let mut sides_content = String::new();
+        let corners = read_corners(img_file);
 
-    println!("{} {}x{} shift={} sqr={}",
-             img_file,
-             width,
-             height,
-             shift,
-             sqr);
+        for side in 0..4 {
 
-    let wnd_size = renderer.window().unwrap().size();
-    if sqr >= wnd_size.0 as usize || sqr >= wnd_size.1 as usize {
-        panic!("{} too big {}x{} window is just {}x{}",
-               img_file,
-               sqr,
-               sqr,
-               wnd_size.0,
-               wnd_size.1);
-    }
+            let mut best_corner_delta = usize::max_value();
+            let mut best_corner_angle = 0f64;
 
-    // Resize window
-    /*renderer.window_mut()
-        .unwrap()
-        .set_size(sqr as u32, sqr as u32)
-        .unwrap();*/
+            let mut r = -5f64;
+            'rotating: loop {
+                if corners.is_some() {
+                    break;
+                }
 
-    for side in 0..4 {
+                let angle = (90 * side) as f64 + r;
+                //println!("angle={}", angle);
 
-        let mut best_corner_delta = usize::max_value();
-        let mut best_corner_angle = 0f64;
+                let rv = rotate_and_find_corners(&img,
+                                                 angle,
+                                                 shift,
+                                                 sqr,
+                                                 params,
+                                                 true,
+                                                 &mut pixels);
 
-        let mut r = -5f64;
-        'rotating: loop {
+                let top_x = rv.0;
+                let bot_x = rv.2;
 
-            let angle = (90 * side) as f64 + r;
-            //println!("angle={}", angle);
+                let corner_delta = cmp::max(top_x, bot_x) - cmp::min(top_x, bot_x);
 
-            let rv = rotate_and_find_corners(&mut renderer,
-                                             &texture,
-                                             angle,
-                                             shift as usize,
-                                             sqr,
-                                             width,
-                                             height,
-                                             true);
+                //println!("corner_delta={}", corner_delta);
+                if corner_delta <= best_corner_delta {
+                    best_corner_delta = corner_delta;
+                    best_corner_angle = angle;
+                }
 
-            let top_x = rv.0;
-            let bot_x = rv.2;
-            let pixels = rv.4;
+                let mut hud = Hud {
+                    labels: vec![],
+                    lines: vec![format!("{} side {} angle {:.2} corner delta {}",
+                                        img_file,
+                                        side,
+                                        angle,
+                                        corner_delta),
+                                "C restart rotation  A autorotate  Esc next side".to_string()],
+                    textures: None,
+                };
+                match display_pixels(&pixels,
+                                     sqr,
+                                     sdl_context,
+                                     &mut renderer,
+                                     display_state,
+                                     &mut hud,
+                                     font) {
+                    UserAction::Quit => break 'rotating,
+                    UserAction::Compute => {
+                        r -= 1f64;
+                        best_corner_delta = usize::max_value();
+                        continue 'rotating;
+                    }
+                    _ => {}
+                }
 
-            let corner_delta = cmp::max(top_x, bot_x) - cmp::min(top_x, bot_x);
+                if corner_delta > 10 {
+                    r += 1f64;
+                } else if corner_delta > 2 {
+                    r += 0.5f64;
+                } else {
+                    r += 0.02f64;
+                }
+                if r > 9f64 {
+                    break;
+                }
+            }
 
-            //println!("corner_delta={}", corner_delta);
-            if corner_delta <= best_corner_delta {
-                best_corner_delta = corner_delta;
+            let override_corners = corners.as_ref()
+                .map(|c| corners_side(c, side, shift, width as usize, height as usize));
+            if let Some((angle, _, _)) = override_corners {
                 best_corner_angle = angle;
             }
 
-            match display_pixels(&pixels, sqr, sdl_context, &mut renderer, display_state) {
-                UserAction::Quit => break 'rotating,
-                UserAction::Compute => {
-                    r -= 1f64;
-                    best_corner_delta = usize::max_value();
-                    continue 'rotating;
+            println!("best_corner_angle={}", best_corner_angle);
+
+            let rv = rotate_and_find_corners(&img,
+                                             best_corner_angle,
+                                             shift,
+                                             sqr,
+                                             params,
+                                             false,
+                                             &mut pixels);
+
+            let mut top_x = rv.0;
+            let mut top_y = rv.1;
+            let mut bot_x = rv.2;
+            let mut bot_y = rv.3;
+            let bounds = rv.4;
+
+            if let Some((_, top, bot)) = override_corners {
+                let top = nearest_border(&pixels, sqr, bounds, top.0, top.1);
+                let bot = nearest_border(&pixels, sqr, bounds, bot.0, bot.1);
+                println!("corners from {}: top {:?} bottom {:?} instead of {:?} {:?}",
+                         corners_file,
+                         top,
+                         bot,
+                         (top_x, top_y),
+                         (bot_x, bot_y));
+                top_x = top.0;
+                top_y = top.1;
+                bot_x = bot.0;
+                bot_y = bot.1;
+            }
+
+            // Save all border points to file
+            if side == 0 {
+                let border = get_points(&pixels, sqr, bounds, RED_MASK_BORDER);
+                save_points(&border, img_file, &format!("{}.txt", png_no));
+            }
+
+            // Save left edge coordinates to file
+            let edge = find_edge(&mut pixels,
+                                 sqr,
+                                 bounds,
+                                 top_x,
+                                 top_y,
+                                 bot_x,
+                                 bot_y,
+                                 params.edge_cut);
+            save_points(&edge, img_file, &format!("{}.{}.txt", png_no, side));
+
+            sides_content += &format!("{},{},{},{},{},{},{},{},{}\n",
+                                      side,
+                                      best_corner_angle,
+                                      top_x,
+                                      top_y,
+                                      bot_x,
+                                      bot_y,
+                                      shift,
+                                      width,
+                                      height);
+
+            let mut hud = Hud {
+                labels: vec![],
+                lines: vec![format!("{} side {} best angle {:.2} saved {}.{}.txt",
+                                    img_file,
+                                    side,
+                                    best_corner_angle,
+                                    png_no,
+                                    side),
+                            if corners.is_some() {
+                                "corners from .corners file  N find corners again".to_string()
+                            } else {
+                                "click 4 corners on this side to set them manually".to_string()
+                            }],
+                textures: None,
+            };
+
+            // Make .done file so that we can detect processed pngs
+            if side == 3 {
+                write_text_file(&format!("{}.sides", img_file), &sides_content);
+                write_done_file(img_file);
+
+                // Suspect pieces go to review list
+                let problems = check_extraction(img_file, png_no, config);
+                update_review(png_no, &problems);
+                if !problems.is_empty() {
+                    for problem in problems {
+                        println!("WARNING {}: {}", img_file, problem);
+                        hud.lines.push(format!("WARNING {}", problem));
+                    }
                 }
-                _ => {}
             }
 
-            if corner_delta > 10 {
-                r += 1f64;
-            } else if corner_delta > 2 {
-                r += 0.5f64;
-            } else {
-                r += 0.02f64;
+            // Clicks of one side only make the corners, other side is rotated differently
+            let mut clicked: Vec<(f64, f64)> = vec![];
+            let restart = loop {
+                match display_pixels(&pixels,
+                                     sqr,
+                                     sdl_context,
+                                     &mut renderer,
+                                     display_state,
+                                     &mut hud,
+                                     font) {
+                    UserAction::Click(x, y) => {
+                        let p = unrotate_point(x,
+                                               y,
+                                               best_corner_angle,
+                                               shift,
+                                               width as usize,
+                                               height as usize);
+                        clicked.push((p.0.max(0.0), p.1.max(0.0)));
+                        println!("corner {}/4", clicked.len());
+                        draw_mark(&mut pixels, sqr, x, y, 255, 0, 255);
+                        hud.lines[1] = format!("corner {}/4 clicked", clicked.len());
+                        if clicked.len() == 4 {
+                            let mut content = String::new();
+                            for p in clicked.iter() {
+                                content += &format!("{},{}\n", p.0 as usize, p.1 as usize);
+                            }
+                            write_text_file(&corners_file, &content);
+                            break true;
+                        }
+                    }
+                    UserAction::Cancel if corners.is_some() => {
+                        println!("{:?}", fs::remove_file(&corners_file));
+                        break true;
+                    }
+                    _ => break false,
+                }
+            };
+
+
+            if restart {
+                continue 'extracting;
             }
-            if r > 9f64 {
-                break;
+        }
+        break;
+    }
+}
+
+fn span_y(points: &Vec<(usize, usize)>) -> usize {
+    let min_y = points.iter().map(|p| p.1).min().unwrap_or(0);
+    let max_y = points.iter().map(|p| p.1).max().unwrap_or(0);
+    max_y - min_y
+}
+
+// Sanity checks of extracted piece: edge lengths, edges covering outline, corners
+// forming rectangle and piece not touching png border. Returns found problems.
+fn check_extraction(png_file: &str,
+                    png_no: usize,
+                    config: &HashMap<String, String>)
+                    -> Vec<String> {
+    let mut problems = vec![];
+    let tolerance = config_f64(config, "check_tolerance").unwrap_or(0.3);
+    let dir = Path::new(png_file).parent().unwrap();
+
+    // Edges should be similarly long and in limits from config
+    let mut edges = vec![];
+    for side in 0..4 {
+        let edge_file = dir.join(format!("{}.{}.txt", png_no, side));
+        if !edge_file.exists() {
+            problems.push(format!("{} missing", edge_file.display()));
+            return problems;
+        }
+        edges.push(read_txt(edge_file.to_str().unwrap()));
+    }
+    let spans: Vec<usize> = edges.iter().map(span_y).collect();
+    let mean = spans.iter().sum::<usize>() as f64 / 4.0;
+    let min_len = config_f64(config, "edge_min_len").unwrap_or(0.0);
+    let max_len = config_f64(config, "edge_max_len").unwrap_or(f64::MAX);
+    for (side, &span) in spans.iter().enumerate() {
+        let len = span as f64;
+        if (len - mean).abs() > tolerance * mean || len < min_len || len > max_len {
+            problems.push(format!("edge {}.{} length {} (mean {:.0})",
+                                  png_no,
+                                  side,
+                                  span,
+                                  mean));
+        }
+    }
+
+    // Four edges should have about the same points as the whole outline
+    let outline_file = dir.join(format!("{}.txt", png_no));
+    if outline_file.exists() {
+        let outline = read_txt(outline_file.to_str().unwrap());
+        let edges_len: usize = edges.iter().map(|e| e.len()).sum();
+        let coverage = edges_len as f64 / cmp::max(outline.len(), 1) as f64;
+        if (coverage - 1.0).abs() > tolerance / 2.0 {
+            problems.push(format!("edges have {:.0}% of outline points", 100.0 * coverage));
+        }
+    }
+
+    // Corners: opposite sides and diagonals of the same length, bottom corner of each
+    // side is top corner of other side
+    if let Some(sides) = read_piece_sides(png_file) {
+        let len: Vec<f64> = sides.iter().map(|s| distance(s.top, s.bot)).collect();
+        let mean_len = len.iter().sum::<f64>() / 4.0;
+        for k in 0..2 {
+            if (len[k] - len[k + 2]).abs() > tolerance / 2.0 * mean_len {
+                problems.push(format!("sides {} and {} differ {:.0}/{:.0}",
+                                      k,
+                                      k + 2,
+                                      len[k],
+                                      len[k + 2]));
+            }
+        }
+        let diag_a = distance(sides[0].top, sides[2].top);
+        let diag_b = distance(sides[1].top, sides[3].top);
+        if (diag_a - diag_b).abs() > tolerance / 2.0 * (diag_a + diag_b) / 2.0 {
+            problems.push(format!("corners are not rectangle, diagonals {:.0}/{:.0}",
+                                  diag_a,
+                                  diag_b));
+        }
+        for k in 0..4 {
+            let nearest = (0..4)
+                .filter(|j| *j != k)
+                .map(|j| distance(sides[k].bot, sides[j].top))
+                .fold(f64::MAX, f64::min);
+            if nearest > tolerance / 3.0 * mean_len {
+                problems.push(format!("side {} bottom corner is {:.0} from other corners",
+                                      k,
+                                      nearest));
+            }
+        }
+    }
+
+    // Material on png border means piece was cut when cropping
+    let img = image::open(Path::new(png_file)).unwrap().to_luma();
+    let (width, height) = img.dimensions();
+    let mut touching = 0;
+    for y in 0..height {
+        for x in 0..width {
+            if x > 0 && y > 0 && x < width - 1 && y < height - 1 {
+                continue;
+            }
+            if img.get_pixel(x, y).data[0] <= 127 {
+                touching += 1;
+            }
+        }
+    }
+    if touching > 0 {
+        problems.push(format!("piece touches png border in {} pixels", touching));
+    }
+
+    problems
+}
+
+// Replace line of png in review list, pieces without problems are removed from it
+fn update_review(png_no: usize, problems: &Vec<String>) {
+    let mut content = String::new();
+    if let Ok(mut file) = File::open(REVIEW_FILE) {
+        file.read_to_string(&mut content).unwrap();
+    }
+    let prefix = format!("{}:", png_no);
+    let mut res = String::new();
+    for line in content.lines().filter(|l| !l.starts_with(&prefix)) {
+        res += line;
+        res += "\n";
+    }
+    if !problems.is_empty() {
+        res += &format!("{} {}\n", prefix, problems.join("; "));
+    }
+    if res != content {
+        write_text_file(REVIEW_FILE, &res);
+    }
+}
+
+// Check all extracted pngs and write review list, with rerun delete .done files of
+// suspect pieces so that they are extracted again
+fn review_extractions(data_dir: &str, rerun: bool, config: &HashMap<String, String>) {
+    let mut content = String::new();
+    let mut suspects = 0;
+    for entry in fs::read_dir(data_dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension().and_then(OsStr::to_str) != Some("png") {
+            continue;
+        }
+        let png_no: usize = path.file_stem().unwrap().to_str().unwrap().parse().unwrap();
+        let png_file = path.into_os_string().into_string().unwrap();
+        if !Path::new(&format!("{}.done", png_file)).exists() {
+            continue;
+        }
+        let problems = check_extraction(&png_file, png_no, config);
+        if problems.is_empty() {
+            continue;
+        }
+        suspects += 1;
+        let line = format!("{}: {}", png_no, problems.join("; "));
+        println!("{}", line);
+        content += &line;
+        content += "\n";
+        if rerun {
+            println!("{:?}", fs::remove_file(format!("{}.done", png_file)));
+        }
+    }
+    write_text_file(REVIEW_FILE, &content);
+    println!("{} suspect pieces", suspects);
+}
+
+// Solve 8 unknowns of homography mapping src points to dst points. Returns 3x3 matrix
+// with h[8] = 1, None if 3 of the points lie on one line.
+fn homography(src: &[(f64, f64); 4], dst: &[(f64, f64); 4]) -> Option<[f64; 9]> {
+
+    // Two equations for each point pair, last column is right side
+    let mut m = [[0f64; 9]; 8];
+    for i in 0..4 {
+        let (x, y) = src[i];
+        let (u, v) = dst[i];
+        m[2 * i] = [x, y, 1.0, 0.0, 0.0, 0.0, -u * x, -u * y, u];
+        m[2 * i + 1] = [0.0, 0.0, 0.0, x, y, 1.0, -v * x, -v * y, v];
+    }
+
+    // Gaussian elimination with partial pivoting
+    for col in 0..8 {
+        let mut pivot = col;
+        for row in col + 1..8 {
+            if m[row][col].abs() > m[pivot][col].abs() {
+                pivot = row;
+            }
+        }
+        m.swap(col, pivot);
+        if m[col][col].abs() < 1e-12 {
+            return None;
+        }
+        for row in 0..8 {
+            if row == col {
+                continue;
+            }
+            let f = m[row][col] / m[col][col];
+            let pivot_row = m[col];
+            for (k, value) in m[row].iter_mut().enumerate().skip(col) {
+                *value -= f * pivot_row[k];
             }
         }
+    }
+
+    let mut h = [1f64; 9];
+    for i in 0..8 {
+        h[i] = m[i][8] / m[i][i];
+    }
+    Some(h)
+}
 
-        println!("best_corner_angle={}", best_corner_angle);
+fn apply_homography(h: &[f64; 9], x: f64, y: f64) -> (f64, f64) {
+    let w = h[6] * x + h[7] * y + h[8];
+    ((h[0] * x + h[1] * y + h[2]) / w, (h[3] * x + h[4] * y + h[5]) / w)
+}
 
-        let rv = rotate_and_find_corners(&mut renderer,
-                                         &texture,
-                                         best_corner_angle as f64,
-                                         shift as usize,
-                                         sqr,
-                                         width,
-                                         height,
-                                         false);
+fn distance(a: (f64, f64), b: (f64, f64)) -> f64 {
+    ((a.0 - b.0).powi(2) + (a.1 - b.1).powi(2)).sqrt()
+}
 
-        let top_x = rv.0;
-        let top_y = rv.1;
-        let bot_x = rv.2;
-        let bot_y = rv.3;
-        let mut pixels = rv.4;
-        let bounds = rv.5;
+// Undistort photo so that the 4 reference points (top-left, top-right, bottom-right,
+// bottom-left corner of sheet) become a rectangle. Size of rectangle is sheet_width_mm x
+// sheet_height_mm from config, radial lens distortion is corrected with lens_k1. Returns
+// rectified image and its pixels per mm if sheet size is known, None if points do not
+// make quadrilateral.
+fn rectify_image(img: &image::DynamicImage,
+                 points: &[(f64, f64); 4],
+                 config: &HashMap<String, String>)
+                 -> Option<(image::RgbImage, Option<f64>)> {
+
+    let src = img.to_rgb();
+
+    // Keep resolution of the photo
+    let mut width = (distance(points[0], points[1]) + distance(points[3], points[2])) / 2.0;
+    let mut height = (distance(points[0], points[3]) + distance(points[1], points[2])) / 2.0;
+    let mut ppm = None;
+    if let (Some(w_mm), Some(h_mm)) = (config_f64(config, "sheet_width_mm"),
+                                       config_f64(config, "sheet_height_mm")) {
+        height = width * h_mm / w_mm;
+        ppm = Some(width / w_mm);
+    }
+    if let Some(scale) = ppm {
+        width = width.round();
+        height = height.round();
+        println!("rectified {}x{}, {:.3} pixels per mm", width, height, scale);
+    }
 
-        // Save all border points to file
-        if side == 0 {
-            let border = get_points(&pixels, sqr, bounds, RED_MASK_BORDER);
-            save_points(&border, img_file, &format!("{}.txt", png_no));
+    // Homography from rectified to photo coordinates
+    let rect = [(0.0, 0.0), (width, 0.0), (width, height), (0.0, height)];
+    let h = homography(&rect, points)?;
+
+    // Radial distortion around photo center, radius is normalized to half diagonal
+    let k1 = config_f64(config, "lens_k1").unwrap_or(0.0);
+    let cx = src.width() as f64 / 2.0;
+    let cy = src.height() as f64 / 2.0;
+    let norm = cx * cx + cy * cy;
+
+    let mut res = image::ImageBuffer::from_pixel(width as u32,
+                                                 height as u32,
+                                                 image::Rgb([255u8, 255, 255]));
+    for (x, y, pixel) in res.enumerate_pixels_mut() {
+        let (u, v) = apply_homography(&h, x as f64 + 0.5, y as f64 + 0.5);
+        let f = 1.0 + k1 * ((u - cx).powi(2) + (v - cy).powi(2)) / norm;
+        let sx = cx + (u - cx) * f;
+        let sy = cy + (v - cy) * f;
+        if sx < 0.0 || sy < 0.0 || sx >= src.width() as f64 || sy >= src.height() as f64 {
+            continue;
         }
+        *pixel = *src.get_pixel(sx as u32, sy as u32);
+    }
+    Some((res, ppm))
+}
 
-        // Save left edge coordinates to file
-        let edge = find_edge(&mut pixels, sqr, bounds, top_x, top_y, bot_x, bot_y);
-        save_points(&edge, img_file, &format!("{}.{}.txt", png_no, side));
+// Streaming texture with image pixels
+fn image_texture(renderer: &mut Renderer, img: &image::RgbImage) -> Texture {
+    let (width, height) = img.dimensions();
+    let mut texture =
+        renderer.create_texture_streaming(PixelFormatEnum::RGB24, width, height).unwrap();
+    texture.with_lock(None, |buffer: &mut [u8], pitch: usize| for y in 0..height as usize {
+            for x in 0..width as usize {
+                let pix = img.get_pixel(x as u32, y as u32).data;
+                let offset = y * pitch + x * 3;
+                buffer[offset] = pix[0];
+                buffer[offset + 1] = pix[1];
+                buffer[offset + 2] = pix[2];
+            }
+        })
+        .unwrap();
+    texture
+}
 
-        // Make .done file so that we can detect processed pngs
-        if side == 3 {
-            write_done_file(img_file);
+// Rectify jpg with calibration points, save it next to jpg and remember its scale in
+// config. Returns rectified image and its path, None if points lie on one line.
+fn calibrate_jpg(jpg_file: &str,
+                 img: &image::DynamicImage,
+                 points: &[(f64, f64); 4],
+                 config: &HashMap<String, String>)
+                 -> Option<(image::RgbImage, String)> {
+
+    let (rectified, ppm) = match rectify_image(img, points, config) {
+        Some(rv) => rv,
+        None => {
+            println!("calibration points lie on one line, press K and click them again");
+            return None;
         }
+    };
+    let rectified_file = format!("{}.rectified.png", jpg_file);
+    let fout = &mut File::create(Path::new(&rectified_file)).unwrap();
+    let _ = image::ImageRgb8(rectified.clone()).save(fout, image::PNG);
+    println!("successfully wrote to {}", rectified_file);
 
-        display_pixels(&pixels, sqr, sdl_context, &mut renderer, display_state);
+    if let Some(scale) = ppm {
+        set_config_values(&vec![(scale_key(&rectified_file), scale.to_string())]);
     }
+    Some((rectified, rectified_file))
 }
 
-fn process_jpg(jpg_file: &str, jpg_no: usize, sdl_context: &sdl2::Sdl) {
+fn process_jpg(jpg_file: &str,
+               jpg_no: usize,
+               data_dir: &str,
+               sdl_context: &sdl2::Sdl,
+               config: &HashMap<String, String>) {
 
     let video_subsystem = sdl_context.video().unwrap();
 
-    let window = video_subsystem.window(jpg_file, WND_WIDTH as u32, WND_HEIGHT as u32)
+    let (wnd_width, wnd_height) = window_size(config);
+    let (wnd_width, wnd_height) = viewer_size(&video_subsystem, config, wnd_width, wnd_height);
+
+    let window = video_subsystem.window(jpg_file, wnd_width as u32, wnd_height as u32)
         .position(200, 0)
         .opengl()
         .build()
@@ -1008,13 +1900,13 @@ fn process_jpg(jpg_file: &str, jpg_no: usize, sdl_context: &sdl2::Sdl) {
 
     let mut renderer = window.renderer().build().unwrap();
 
-    let texture = renderer.load_texture(jpg_file).unwrap();
+    let mut texture = renderer.load_texture(jpg_file).unwrap();
 
-    let TextureQuery { width, height, .. } = texture.query();
+    let TextureQuery { mut width, mut height, .. } = texture.query();
 
     let mut event_pump = sdl_context.event_pump().unwrap();
 
-    let dst_rect = Rect::new(0, 0, WND_WIDTH as u32, WND_HEIGHT as u32);
+    let dst_rect = Rect::new(0, 0, wnd_width as u32, wnd_height as u32);
 
     let mut down_x = -1;
     let mut down_y = -1;
@@ -1022,7 +1914,7 @@ fn process_jpg(jpg_file: &str, jpg_no: usize, sdl_context: &sdl2::Sdl) {
 
     // Use the open function to load an image from a Path.
     // ```open``` returns a dynamic image.
-    let img = image::open(&Path::new(jpg_file)).unwrap();
+    let mut img = image::open(Path::new(jpg_file)).unwrap();
 
     // The dimensions method returns the images width and height
     println!("dimensions {:?}", img.dimensions());
@@ -1030,6 +1922,30 @@ fn process_jpg(jpg_file: &str, jpg_no: usize, sdl_context: &sdl2::Sdl) {
     // The color method returns the image's ColorType
     println!("{:?}", img.color());
 
+    // Perspective correction: sheet corners clicked after pressing K are saved to .calib
+    // and pieces are cut from rectified image
+    let calib_file = format!("{}.calib", jpg_file);
+    let mut calib_points: Vec<(f64, f64)> = vec![];
+    let mut calibrating = false;
+    let mut crop_source = jpg_file.to_string();
+    let p = if Path::new(&calib_file).exists() { read_txt(&calib_file) } else { vec![] };
+    if !p.is_empty() && p.len() < 4 {
+        println!("{} must have 4 points, press K to calibrate again", calib_file);
+    }
+    if p.len() >= 4 {
+        let points = [(p[0].0 as f64, p[0].1 as f64),
+                      (p[1].0 as f64, p[1].1 as f64),
+                      (p[2].0 as f64, p[2].1 as f64),
+                      (p[3].0 as f64, p[3].1 as f64)];
+        if let Some((rectified, rectified_file)) = calibrate_jpg(jpg_file, &img, &points, config) {
+            texture = image_texture(&mut renderer, &rectified);
+            width = rectified.width();
+            height = rectified.height();
+            img = image::ImageRgb8(rectified);
+            crop_source = rectified_file;
+        }
+    }
+
     loop {
         for event in event_pump.poll_iter() {
             renderer.clear();
@@ -1037,18 +1953,65 @@ fn process_jpg(jpg_file: &str, jpg_no: usize, sdl_context: &sdl2::Sdl) {
             renderer.present();
             match event {
 
+                Event::KeyDown { keycode: Some(Keycode::K), .. } => {
+                    println!("click sheet corners: top-left, top-right, bottom-right, \
+                              bottom-left");
+                    calib_points.clear();
+                    calibrating = true;
+
+                    // Points must be clicked in original photo
+                    if crop_source != jpg_file {
+                        let orig = image::open(Path::new(jpg_file)).unwrap();
+                        texture = renderer.load_texture(jpg_file).unwrap();
+                        width = orig.width();
+                        height = orig.height();
+                        img = orig;
+                        crop_source = jpg_file.to_string();
+                    }
+                }
+                Event::MouseButtonDown { x, y, .. } if calibrating => {
+                    calib_points.push(((x as u32 * width) as f64 / wnd_width as f64,
+                                       (y as u32 * height) as f64 / wnd_height as f64));
+                    println!("calibration point {}/4", calib_points.len());
+                    if calib_points.len() < 4 {
+                        continue;
+                    }
+                    calibrating = false;
+
+                    let points =
+                        [calib_points[0], calib_points[1], calib_points[2], calib_points[3]];
+                    let (rectified, rectified_file) =
+                        match calibrate_jpg(jpg_file, &img, &points, config) {
+                            Some(rv) => rv,
+                            None => continue,
+                        };
+
+                    // Saved only when usable so that next start does not fail on it
+                    let mut content = String::new();
+                    for p in calib_points.iter() {
+                        content += &format!("{},{}\n", p.0 as usize, p.1 as usize);
+                    }
+                    write_text_file(&calib_file, &content);
+
+                    texture = image_texture(&mut renderer, &rectified);
+                    width = rectified.width();
+                    height = rectified.height();
+                    img = image::ImageRgb8(rectified);
+                    crop_source = rectified_file;
+                }
+                Event::MouseButtonUp { .. } if calibrating || down_x < 0 => {}
                 Event::MouseButtonDown { x, y, .. } => {
                     down_x = x;
                     down_y = y;
                 }
                 Event::MouseButtonUp { x, y, .. } => {
 
-                    let png_file = format!("{}.png", png_no);
+                    let png_file = format!("{}/{}.png", data_dir, png_no);
 
-                    let left = (down_x as u32 * width) / WND_WIDTH as u32;
-                    let top = (down_y as u32 * height) / WND_HEIGHT as u32;
-                    let width = (x as u32 * width) / WND_WIDTH as u32 - left;
-                    let height = (y as u32 * height) / WND_HEIGHT as u32 - top;
+                    let left = (down_x as u32 * width) / wnd_width as u32;
+                    let top = (down_y as u32 * height) / wnd_height as u32;
+                    let width = (x as u32 * width) / wnd_width as u32 - left;
+                    let height = (y as u32 * height) / wnd_height as u32 - top;
 
                     println!("saving {} {},{} {}x{}", png_file, left, top, width, height);
 
@@ -1070,14 +2033,18 @@ fn process_jpg(jpg_file: &str, jpg_no: usize, sdl_context: &sdl2::Sdl) {
                     // Write the contents of this image to the Writer in PNG format.
                     let _ = image::ImageLuma8(imgbuf).save(fout, image::PNG);
 
+                    // Remember where the piece is in jpg to get its colors later
+                    let crop = format!("{},{},{},{},{}\n", crop_source, left, top, width, height);
+                    write_text_file(&format!("{}.crop", png_file), &crop);
+
                     png_no += 1;
                 }
 
                 Event::MouseMotion { x, y, .. } => {
                     let color = pixels::Color::RGB(x as u8, y as u8, 255);
                     if down_x < 0 {
-                        let _ = renderer.line(x as i16, 0, x as i16, WND_HEIGHT as i16, color);
-                        let _ = renderer.line(0, y as i16, WND_WIDTH as i16, y as i16, color);
+                        let _ = renderer.line(x as i16, 0, x as i16, wnd_height as i16, color);
+                        let _ = renderer.line(0, y as i16, wnd_width as i16, y as i16, color);
                     } else {
                         let _ = renderer.rectangle(down_x as i16,
                                                    down_y as i16,
@@ -1149,10 +2116,172 @@ fn read_txt(txt_file: &str) -> Vec<(usize, usize)> {
     return coords;
 }
 
-fn max_xy(coords: &Vec<(usize, usize)>) -> (usize, usize) {
+// Read key = value lines, # starts comment
+fn read_config(cfg_file: &str) -> HashMap<String, String> {
+    let mut config = HashMap::new();
 
-    let mut max_x = 0;
-    let mut max_y = 0;
+    let mut content = String::new();
+    match File::open(cfg_file) {
+        Err(_) => return config, // no config, use defaults
+        Ok(mut file) => {
+            if let Err(why) = file.read_to_string(&mut content) {
+                panic!("couldn't read {}: {}", cfg_file, why);
+            }
+        }
+    }
+
+    for line in content.lines() {
+        let line = line.split('#').next().unwrap().trim();
+        let v: Vec<&str> = line.splitn(2, '=').collect();
+        if v.len() != 2 {
+            continue;
+        }
+        config.insert(v[0].trim().to_string(), v[1].trim().to_string());
+    }
+    config
+}
+
+// Set values in puzzle.cfg, lines with the same keys are replaced, new keys appended
+fn set_config_values(values: &Vec<(String, String)>) {
+    let mut content = String::new();
+    if let Ok(mut file) = File::open(CONFIG_FILE) {
+        file.read_to_string(&mut content).unwrap();
+    }
+
+    let mut written = vec![false; values.len()];
+    let mut res = String::new();
+    for line in content.lines() {
+        let line_key = line.split('#').next().unwrap().split('=').next().unwrap().trim();
+        match values.iter().position(|v| line.contains('=') && v.0 == line_key) {
+            Some(k) if written[k] => {}
+            Some(k) => {
+                res += &format!("{} = {}\n", values[k].0, values[k].1);
+                written[k] = true;
+            }
+            None => {
+                res += line;
+                res += "\n";
+            }
+        }
+    }
+    for (k, (key, value)) in values.iter().enumerate() {
+        if !written[k] {
+            res += &format!("{} = {}\n", key, value);
+        }
+    }
+    write_text_file(CONFIG_FILE, &res);
+}
+
+fn config_str(config: &HashMap<String, String>, key: &str, default: &str) -> String {
+    match config.get(key) {
+        Some(val) => val.clone(),
+        None => default.to_string(),
+    }
+}
+
+fn config_f64(config: &HashMap<String, String>, key: &str) -> Option<f64> {
+    match config.get(key) {
+        Some(val) => {
+            match f64::from_str(val) {
+                Ok(val) => Some(val),
+                Err(_) => panic!("{} in {} is not a number: {}", key, CONFIG_FILE, val),
+            }
+        }
+        None => None,
+    }
+}
+
+fn config_usize(config: &HashMap<String, String>, key: &str) -> Option<usize> {
+    match config.get(key) {
+        Some(val) => {
+            match usize::from_str(val) {
+                Ok(val) => Some(val),
+                Err(_) => panic!("{} in {} is not a whole number: {}", key, CONFIG_FILE, val),
+            }
+        }
+        None => None,
+    }
+}
+
+// Config key with pixels per mm of source image, just file name is used so that it
+// does not matter how the path was written, e.g. scale.12.jpg
+fn scale_key(image_file: &str) -> String {
+    match Path::new(image_file).file_name().and_then(OsStr::to_str) {
+        Some(file_name) => format!("scale.{}", file_name),
+        None => format!("scale.{}", image_file),
+    }
+}
+
+// How much to resize piece so that all pieces have the same pixels per mm. Scale of
+// source jpg is set in config as scale.12.jpg = 11.8 (pixels per mm) or scale
+// for all images. Pieces are resized to compare_scale, by default the smallest scale.
+fn scale_factor(config: &HashMap<String, String>, data_dir: &str, piece_no: usize) -> f64 {
+    let mut compare_scale = f64::MAX;
+    for (key, _) in config.iter() {
+        if key == "scale" || key.starts_with("scale.") {
+            compare_scale = compare_scale.min(config_f64(config, key).unwrap());
+        }
+    }
+    if let Some(scale) = config_f64(config, "compare_scale") {
+        compare_scale = scale;
+    }
+    if compare_scale == f64::MAX {
+        return 1.0; // no scales in config
+    }
+
+    let piece_scale = match read_crop(&format!("{}/{}.png", data_dir, piece_no)) {
+        Some((jpg_file, _, _, _, _)) => config_f64(config, &scale_key(&jpg_file)),
+        None => None,
+    };
+    match piece_scale.or(config_f64(config, "scale")) {
+        Some(scale) => compare_scale / scale,
+        None => 1.0,
+    }
+}
+
+// Resize points by factor, keeps them sorted by y and then by x. When enlarging, gaps
+// between neighbour points are filled with points on line between them.
+fn scale_points(points: &Vec<(usize, usize)>, factor: f64) -> Vec<(usize, usize)> {
+    let scale = |p: (usize, usize)| (p.0 as f64 * factor, p.1 as f64 * factor);
+    let mut res: Vec<(usize, usize)> = points.iter()
+        .map(|p| {
+            let (x, y) = scale(*p);
+            (x.round() as usize, y.round() as usize)
+        })
+        .collect();
+    if factor > 1.0 {
+        let mut sorted = points.clone();
+        sorted.sort();
+        for p in points.iter() {
+            // Each pair of neighbours just once
+            for &(dx, dy) in [(1, 0), (0, 1), (1, 1), (-1, 1)].iter() {
+                if p.0 == 0 && dx < 0 {
+                    continue;
+                }
+                let q = ((p.0 as isize + dx) as usize, p.1 + dy as usize);
+                if sorted.binary_search(&q).is_err() {
+                    continue;
+                }
+                let (px, py) = scale(*p);
+                let (qx, qy) = scale(q);
+                let steps = factor.ceil() as usize;
+                for k in 1..steps {
+                    let t = k as f64 / steps as f64;
+                    res.push(((px + t * (qx - px)).round() as usize,
+                              (py + t * (qy - py)).round() as usize));
+                }
+            }
+        }
+    }
+    res.sort_by_key(|a| (a.1, a.0));
+    res.dedup();
+    res
+}
+
+fn max_xy(coords: &Vec<(usize, usize)>) -> (usize, usize) {
+
+    let mut max_x = 0;
+    let mut max_y = 0;
     for p in coords {
         max_x = cmp::max(p.0, max_x);
         max_y = cmp::max(p.1, max_y);
@@ -1178,6 +2307,19 @@ fn draw_coords(pixels: &mut Vec<u8>,
     }
 }
 
+// Small square around x,y, e.g. where user clicked
+fn draw_mark(pixels: &mut Vec<u8>, sqr: usize, x: usize, y: usize, r: u8, g: u8, b: u8) {
+    let size = cmp::max(sqr / 200, 3);
+    for my in y.saturating_sub(size)..cmp::min(y + size + 1, sqr) {
+        for mx in x.saturating_sub(size)..cmp::min(x + size + 1, sqr) {
+            let offset = 3 * (sqr * my + mx);
+            pixels[offset] = r;
+            pixels[offset + 1] = g;
+            pixels[offset + 2] = b;
+        }
+    }
+}
+
 fn draw_edge(pixels: &mut Vec<u8>,
              edges: &Vec<EdgeInfo>,
              e_index: usize,
@@ -1228,39 +2370,398 @@ fn flip_coords(coords: &Vec<(usize, usize)>) -> Vec<(usize, usize)> {
     return res;
 }
 
-fn rotate_piece(points: &Vec<(usize, usize)>, side: usize) -> Vec<(usize, usize)> {
+// Rotate points clockwise by side * 90 degrees so that given side is on the left
+fn rotate_coords(points: &Vec<(usize, usize)>, side: usize) -> Vec<(usize, usize)> {
 
     let max = max_xy(&points);
     let mut res = Vec::with_capacity(points.len());
 
     if side == 0 {
         for p in points.iter() {
-            res.push((p.0 / 2, p.1 / 2));
+            res.push((p.0, p.1));
         }
     } else if side == 1 {
         for p in points.iter() {
-            res.push(((max.1 - p.1) / 2, p.0 / 2));
+            res.push((max.1 - p.1, p.0));
         }
     } else if side == 2 {
         for p in points.iter() {
-            res.push(((max.0 - p.0) / 2, (max.1 - p.1) / 2));
+            res.push((max.0 - p.0, max.1 - p.1));
         }
     } else {
         for p in points.iter() {
-            res.push((p.1 / 2, (max.0 - p.0) / 2));
+            res.push((p.1, max.0 - p.0));
         }
     }
     res
 }
 
-// Used to draw piece with solved edge with white
+// Rotated piece in half size for displaying
+fn rotate_piece(points: &Vec<(usize, usize)>, side: usize) -> Vec<(usize, usize)> {
+    rotate_coords(points, side).iter().map(|p| (p.0 / 2, p.1 / 2)).collect()
+}
+
+// Return edge_no of drawn piece edge nearest to x,y
+fn clicked_edge(drawn_pieces: &Vec<DrawnPiece>, x: usize, y: usize) -> Option<usize> {
+
+    // Find piece with outline nearest to click
+    let mut best_dst = usize::max_value();
+    let mut best_piece = None;
+    for piece in drawn_pieces.iter() {
+        for p in piece.points.iter() {
+            let dx = (p.0 + piece.left) as isize - x as isize;
+            let dy = (p.1 + piece.top) as isize - y as isize;
+            let dst = (dx * dx + dy * dy) as usize;
+            if dst < best_dst {
+                best_dst = dst;
+                best_piece = Some(piece);
+            }
+        }
+    }
+
+    // Click must be close to the outline
+    let piece = match best_piece {
+        Some(piece) if best_dst <= 20 * 20 => piece,
+        _ => return None,
+    };
+
+    let max = max_xy(&piece.points);
+    let dir = nearest_dir(x.saturating_sub(piece.left), y.saturating_sub(piece.top), max);
+
+    Some(4 * piece.piece_no + ((piece.rotation + dir) & 3))
+}
+
+// Which side of bounding box 0,0..max is nearest: 0=left, 1=bottom, 2=right, 3=top
+fn nearest_dir(x: usize, y: usize, max: (usize, usize)) -> usize {
+    let px = cmp::min(x, max.0);
+    let py = cmp::min(y, max.1);
+    let side_dst = [px, max.1 - py, max.0 - px, py];
+    let mut dir = 0;
+    for i in 1..4 {
+        if side_dst[i] < side_dst[dir] {
+            dir = i;
+        }
+    }
+    dir
+}
+
+// Grid cell delta for direction 0=left, 1=bottom, 2=right, 3=top
+fn dir_delta(dir: usize) -> (isize, isize) {
+    match dir & 3 {
+        0 => (-1, 0),
+        1 => (0, 1),
+        2 => (1, 0),
+        _ => (0, -1),
+    }
+}
+
+// Place pieces connected by solved edges into grid. Piece with rotation r has
+// side r on the left, r+1 on the bottom, r+2 on the right and r+3 on the top.
+// Returns placements and contradictions, e.g. two pieces claiming the same cell.
+fn place_pieces(edges: &Vec<EdgeInfo>,
+                edge_nums: &HashMap<usize, usize>)
+                -> (HashMap<usize, Placement>, Vec<String>) {
+
+    let mut placements: HashMap<usize, Placement> = HashMap::new();
+    let mut contradictions = vec![];
+
+    // Piece in each cell of cluster
+    let mut cells: HashMap<(usize, isize, isize), usize> = HashMap::new();
+
+    // Start clusters from the lowest piece numbers so that layout is stable
+    let mut solved_pieces = vec![];
+    for edge in edges.iter() {
+        if edge.solved_index != usize::max_value() {
+            solved_pieces.push(edge.edge_no >> 2);
+        }
+    }
+    solved_pieces.sort();
+    solved_pieces.dedup();
+
+    let mut cluster = 0;
+    for start in solved_pieces {
+        if placements.contains_key(&start) {
+            continue;
+        }
+        placements.insert(start,
+                          Placement {
+                              x: 0,
+                              y: 0,
+                              rotation: 0,
+                              cluster,
+                          });
+        cells.insert((cluster, 0, 0), start);
+
+        // Walk solved edges to neighbours
+        let mut queue = vec![start];
+        while let Some(piece_no) = queue.pop() {
+            let p = placements[&piece_no];
+            for side in 0..4 {
+                let e_index = match edge_nums.get(&(4 * piece_no + side)) {
+                    Some(e_index) => *e_index,
+                    None => continue,
+                };
+                let solved_index = edges[e_index].solved_index;
+                if solved_index == usize::max_value() {
+                    continue;
+                }
+                let f_no = edges[solved_index].edge_no;
+                let dir = (side + 4 - p.rotation) & 3;
+                let delta = dir_delta(dir);
+
+                // Neighbour edge must face back
+                let q = Placement {
+                    x: p.x + delta.0,
+                    y: p.y + delta.1,
+                    rotation: ((f_no & 3) + 4 - ((dir + 2) & 3)) & 3,
+                    cluster,
+                };
+                let q_no = f_no >> 2;
+                if let Some(prev) = placements.get(&q_no) {
+                    // Already placed, must be on the same cell with same rotation
+                    if piece_no < q_no &&
+                       (prev.x != q.x || prev.y != q.y || prev.rotation != q.rotation) {
+                        contradictions.push(format!("{}->{} puts {} at {},{}r{} but it is \
+                                                     at {},{}r{}",
+                                                    edge_name(4 * piece_no + side),
+                                                    edge_name(f_no),
+                                                    q_no,
+                                                    q.x,
+                                                    q.y,
+                                                    q.rotation,
+                                                    prev.x,
+                                                    prev.y,
+                                                    prev.rotation));
+                    }
+                    continue;
+                }
+                if let Some(other) = cells.get(&(cluster, q.x, q.y)) {
+                    contradictions.push(format!("{} and {} claim the same cell {},{} \
+                                                 (via {}->{})",
+                                                other,
+                                                q_no,
+                                                q.x,
+                                                q.y,
+                                                edge_name(4 * piece_no + side),
+                                                edge_name(f_no)));
+                }
+                cells.insert((cluster, q.x, q.y), q_no);
+                placements.insert(q_no, q);
+                queue.push(q_no);
+            }
+        }
+        cluster += 1;
+    }
+    (placements, contradictions)
+}
+
+// Unsolved edges of pieces in the biggest cluster that face an empty cell. Edges facing
+// cells with more placed neighbours go first, then edges whose quad has D placed
+// (side_minus solved).
+fn frontier_edges(edges: &Vec<EdgeInfo>, edge_nums: &HashMap<usize, usize>) -> Vec<usize> {
+    let (placements, _) = place_pieces(edges, edge_nums);
+
+    let mut sizes = vec![];
+    for p in placements.values() {
+        while sizes.len() <= p.cluster {
+            sizes.push(0);
+        }
+        sizes[p.cluster] += 1;
+    }
+    let biggest = match (0..sizes.len()).max_by_key(|c| sizes[*c]) {
+        Some(biggest) => biggest,
+        None => return vec![],
+    };
+
+    let mut cells = HashMap::new();
+    for (piece_no, p) in placements.iter() {
+        if p.cluster == biggest {
+            cells.insert((p.x, p.y), *piece_no);
+        }
+    }
+
+    // (placed neighbours, side_minus solved, edge_no, edge_index)
+    let mut frontier = vec![];
+    for (piece_no, p) in placements.iter() {
+        if p.cluster != biggest {
+            continue;
+        }
+        for dir in 0..4 {
+            let e_no = 4 * piece_no + ((p.rotation + dir) & 3);
+            let e_index = match edge_nums.get(&e_no) {
+                Some(e_index) => *e_index,
+                None => continue,
+            };
+            if edges[e_index].solved_index != usize::max_value() {
+                continue;
+            }
+            let delta = dir_delta(dir);
+            let target = (p.x + delta.0, p.y + delta.1);
+            if cells.contains_key(&target) {
+                continue;
+            }
+            let mut neighbours = 0;
+            for d in 0..4 {
+                let delta = dir_delta(d);
+                if cells.contains_key(&(target.0 + delta.0, target.1 + delta.1)) {
+                    neighbours += 1;
+                }
+            }
+            let d_placed = match edge_nums.get(&side_minus(e_no)) {
+                Some(i) => edges[*i].solved_index != usize::max_value(),
+                None => false,
+            };
+            frontier.push((neighbours, d_placed, e_no, e_index));
+        }
+    }
+    frontier.sort_by(|a, b| (b.0, b.1, a.2).cmp(&(a.0, a.1, b.2)));
+
+    println!("frontier of cluster with {} pieces: {} edges",
+             sizes[biggest],
+             frontier.len());
+    frontier.iter().map(|f| f.3).collect()
+}
+
+// Draw all clusters of solved pieces into new pixels buffer, unsolved edges of placed
+// pieces (frontier) are yellow. If colors are given pieces are drawn with them instead.
+// Returns pixels, their square size and piece labels.
+fn draw_assembled(edges: &Vec<EdgeInfo>,
+                  edge_nums: &HashMap<usize, usize>,
+                  pieces: &Pieces,
+                  placements: &HashMap<usize, Placement>,
+                  colors: Option<&HashMap<usize, (u8, u8, u8)>>)
+                  -> (Vec<u8>, usize, Vec<(usize, usize, String)>) {
+
+    // Cell is average edge length, pieces are drawn in half size (see rotate_piece)
+    let mut len_sum = 0;
+    for edge in edges.iter() {
+        len_sum += edge.max_y;
+    }
+    let cell = cmp::max(len_sum / cmp::max(edges.len(), 1) / 2, 1);
+
+    // Grid bounds of each cluster
+    let mut clusters: Vec<(isize, isize, isize, isize)> = vec![];
+    for p in placements.values() {
+        while clusters.len() <= p.cluster {
+            clusters.push((isize::max_value(), isize::max_value(), isize::min_value(),
+                           isize::min_value()));
+        }
+        let c = &mut clusters[p.cluster];
+        c.0 = cmp::min(c.0, p.x);
+        c.1 = cmp::min(c.1, p.y);
+        c.2 = cmp::max(c.2, p.x);
+        c.3 = cmp::max(c.3, p.y);
+    }
+
+    // Put clusters in rows, biggest first, one empty cell between them
+    let mut order: Vec<usize> = (0..clusters.len()).collect();
+    order.sort_by(|a, b| {
+        let size_a = (clusters[*a].2 - clusters[*a].0 + 1) * (clusters[*a].3 - clusters[*a].1 + 1);
+        let size_b = (clusters[*b].2 - clusters[*b].0 + 1) * (clusters[*b].3 - clusters[*b].1 + 1);
+        size_b.cmp(&size_a)
+    });
+    let mut area = 0;
+    let mut max_w = 0;
+    for c in clusters.iter() {
+        area += (c.2 - c.0 + 2) * (c.3 - c.1 + 2);
+        max_w = cmp::max(max_w, c.2 - c.0 + 2);
+    }
+    let row_limit = cmp::max(max_w, (area as f64).sqrt().ceil() as isize);
+
+    let mut origins = vec![(0isize, 0isize); clusters.len()];
+    let mut row_x = 0;
+    let mut row_y = 0;
+    let mut row_h = 0;
+    let mut grid_w = 0;
+    for c_index in order {
+        let c = clusters[c_index];
+        let w = c.2 - c.0 + 2;
+        let h = c.3 - c.1 + 2;
+        if row_x > 0 && row_x + w > row_limit {
+            row_x = 0;
+            row_y += row_h;
+            row_h = 0;
+        }
+        origins[c_index] = (row_x - c.0, row_y - c.1);
+        row_x += w;
+        row_h = cmp::max(row_h, h);
+        grid_w = cmp::max(grid_w, row_x);
+    }
+    let grid_h = row_y + row_h;
+
+    // Square with one cell margin, modulo 4 to play well with texture pitch
+    let sqr = ((cmp::max(grid_w, grid_h) as usize + 2) * cell + 5) & !3usize;
+    let mut pixels = vec![0; 3 * sqr * sqr];
+    let mut labels = vec![];
+
+    for (piece_no, p) in placements.iter() {
+        let points = match pieces.get(piece_no) {
+            Some(points) => rotate_piece(points, p.rotation),
+            None => continue,
+        };
+        let max = max_xy(&points);
+        let origin = origins[p.cluster];
+
+        // Center piece in its cell
+        let cx = ((p.x + origin.0) as usize + 1) * cell + cell / 2;
+        let cy = ((p.y + origin.1) as usize + 1) * cell + cell / 2;
+        let left = cx.saturating_sub(max.0 / 2);
+        let top = cy.saturating_sub(max.1 / 2);
+
+        let mut frontier = [false; 4];
+        for (dir, unsolved) in frontier.iter_mut().enumerate() {
+            *unsolved = match edge_nums.get(&(4 * piece_no + ((p.rotation + dir) & 3))) {
+                Some(e_index) => edges[*e_index].solved_index == usize::max_value(),
+                None => false,
+            };
+        }
+
+        for pt in points.iter() {
+            let x = pt.0 + left;
+            let y = pt.1 + top;
+            if x >= sqr || y >= sqr {
+                continue;
+            }
+            let offset = 3 * (sqr * y + x);
+            if let Some(col) = colors.and_then(|c| c.get(piece_no)) {
+                pixels[offset] = col.0;
+                pixels[offset + 1] = col.1;
+                pixels[offset + 2] = col.2;
+            } else if frontier[nearest_dir(pt.0, pt.1, max)] {
+                pixels[offset] = 255;
+                pixels[offset + 1] = 255;
+                pixels[offset + 2] = 0;
+            } else {
+                pixels[offset] = 255;
+                pixels[offset + 1] = 255;
+                pixels[offset + 2] = 255;
+            }
+        }
+        labels.push((cx - cmp::min(cx, 10), cy - cmp::min(cy, 10), format!("{}", piece_no)));
+    }
+
+    (pixels, sqr, labels)
+}
+
+// Solved pieces are coloured by how they were solved: manual white, auto cyan, imported
+// grey. Manual wins if piece has edges with more sources.
 fn piece_col(edges: &Vec<EdgeInfo>, piece_no: usize, r: u8, g: u8, b: u8) -> (u8, u8, u8) {
+    let mut res = (r, g, b);
     for edge in edges {
-        if edge.solved_index != usize::max_value() && edge.edge_no >> 2 == piece_no {
-            return (255, 255, 255);
+        if edge.solved_index == usize::max_value() || edge.edge_no >> 2 != piece_no {
+            continue;
+        }
+        match edge.solved_source.as_str() {
+            "manual" => return (255, 255, 255),
+            "auto" => res = (0, 255, 255),
+            _ => {
+                if res == (r, g, b) {
+                    res = (160, 160, 160);
+                }
+            }
         }
     }
-    (r, g, b)
+    res
 }
 
 fn compare_edge_with_others(edges: &mut Vec<EdgeInfo>,
@@ -1314,13 +2815,24 @@ fn compare_edges_one_way(edges: &Vec<EdgeInfo>, index_a: usize, index_b: usize)
         return edge_a.diff_to[index_b];
     }
 
-    // Needed to flip
+    squared_dst_one_way(edges, index_a, index_b).0
+}
+
+fn compare_edges(edges: &Vec<EdgeInfo>, index_a: usize, index_b: usize) -> usize {
+    compare_edges_one_way(edges, index_a, index_b) +
+        compare_edges_one_way(edges, index_b, index_a)
+}
+
+// Flipped points of a compared to b: sum of squared distances and number of points
+// closer then COVERAGE_DST
+fn squared_dst_one_way(edges: &Vec<EdgeInfo>, index_a: usize, index_b: usize) -> (usize, usize) {
+    let edge_a = &edges[index_a];
     let a_max_x = edge_a.max_x as isize;
     let a_max_y = edge_a.max_y as isize;
 
     let mut diff = 0;
+    let mut covered = 0;
     for a in edge_a.points.iter() {
-        // Flipped a
         let flipped_x = a_max_x - (a.0 as isize);
         let flipped_y = a_max_y - (a.1 as isize);
         let mut best_dst = isize::max_value();
@@ -1329,14 +2841,167 @@ fn compare_edges_one_way(edges: &Vec<EdgeInfo>, index_a: usize, index_b: usize)
             let dy = flipped_y - (b.1 as isize);
             best_dst = cmp::min(best_dst, dx * dx + dy * dy);
         }
-        diff += best_dst;
+        diff += best_dst as usize;
+        if best_dst <= COVERAGE_DST * COVERAGE_DST {
+            covered += 1;
+        }
     }
-    return diff as usize;
+    (diff, covered)
 }
 
-fn compare_edges(edges: &Vec<EdgeInfo>, index_a: usize, index_b: usize) -> usize {
-    return compare_edges_one_way(edges, index_a, index_b) +
-           compare_edges_one_way(edges, index_b, index_a);
+// Similarity that does not depend on edge length so it can be compared between puzzles.
+// It's reported next to diffs, candidates are still ranked by diffs which are cached in
+// diff_to.
+fn edge_similarity(edges: &Vec<EdgeInfo>, index_a: usize, index_b: usize) -> EdgeSimilarity {
+    let len_a = cmp::max(edges[index_a].points.len(), 1);
+    let len_b = cmp::max(edges[index_b].points.len(), 1);
+    let (diff_a, covered_a) = squared_dst_one_way(edges, index_a, index_b);
+    let (diff_b, covered_b) = squared_dst_one_way(edges, index_b, index_a);
+    EdgeSimilarity {
+        rms: ((diff_a + diff_b) as f64 / (len_a + len_b) as f64).sqrt(),
+        coverage_a: covered_a as f64 / len_a as f64,
+        coverage_b: covered_b as f64 / len_b as f64,
+    }
+}
+
+// How much better is the best candidate then the runner-up: 0 the same, 1 perfect match.
+// Edges of the same piece are skipped, best_diff must be computed.
+fn edge_confidence(edges: &Vec<EdgeInfo>, e_index: usize) -> f64 {
+    let e_piece = edges[e_index].edge_no >> 2;
+    let mut rms = vec![];
+    for &(j, _) in edges[e_index].best_diff.iter() {
+        if edges[j].edge_no >> 2 == e_piece {
+            continue;
+        }
+        rms.push(edge_similarity(edges, e_index, j).rms);
+        if rms.len() == 2 {
+            break;
+        }
+    }
+    if rms.len() < 2 || rms[1] == 0.0 {
+        return 0.0;
+    }
+    1.0 - rms[0] / rms[1]
+}
+
+// Compact shape summary of edge: mean x of points in DESCRIPTOR_LEN horizontal stripes
+// of height stripe. With flipped the descriptor is computed for edge rotated by 180
+// degrees so that it can be looked up among descriptors of fitting edges.
+fn edge_descriptor(edge: &EdgeInfo, stripe: usize, flipped: bool) -> Vec<f64> {
+    let mut sums = vec![0f64; DESCRIPTOR_LEN];
+    let mut counts = [0usize; DESCRIPTOR_LEN];
+    for p in edge.points.iter() {
+        let (x, y) = if flipped {
+            (edge.max_x - p.0, edge.max_y - p.1)
+        } else {
+            (p.0, p.1)
+        };
+        let k = cmp::min(y / stripe, DESCRIPTOR_LEN - 1);
+        sums[k] += x as f64;
+        counts[k] += 1;
+    }
+    for k in 0..DESCRIPTOR_LEN {
+        if counts[k] > 0 {
+            sums[k] /= counts[k] as f64;
+        }
+    }
+    sums
+}
+
+fn descriptor_dst(a: &Vec<f64>, b: &Vec<f64>) -> f64 {
+    let mut res = 0.0;
+    for k in 0..a.len() {
+        res += (a[k] - b[k]) * (a[k] - b[k]);
+    }
+    res
+}
+
+// Build KD-tree from given edge indices, returns index of root node
+fn build_kd_tree(descriptors: &Vec<Vec<f64>>,
+                 indices: &mut [usize],
+                 depth: usize,
+                 nodes: &mut Vec<KdNode>)
+                 -> usize {
+    if indices.is_empty() {
+        return usize::max_value();
+    }
+    let axis = depth % DESCRIPTOR_LEN;
+    indices.sort_by(|a, b| descriptors[*a][axis].partial_cmp(&descriptors[*b][axis]).unwrap());
+    let median = indices.len() / 2;
+
+    let node = nodes.len();
+    nodes.push(KdNode {
+        edge_index: indices[median],
+        axis,
+        left: usize::max_value(),
+        right: usize::max_value(),
+    });
+    let (lower, upper) = indices.split_at_mut(median);
+    let left = build_kd_tree(descriptors, lower, depth + 1, nodes);
+    let right = build_kd_tree(descriptors, &mut upper[1..], depth + 1, nodes);
+    nodes[node].left = left;
+    nodes[node].right = right;
+    node
+}
+
+// Find k nearest descriptors to query, best is sorted (distance, edge_index)
+fn kd_nearest(nodes: &Vec<KdNode>,
+              descriptors: &Vec<Vec<f64>>,
+              node: usize,
+              query: &Vec<f64>,
+              k: usize,
+              best: &mut Vec<(f64, usize)>) {
+    if node == usize::max_value() {
+        return;
+    }
+    let n = &nodes[node];
+    let dst = descriptor_dst(query, &descriptors[n.edge_index]);
+    if best.len() < k || dst < best[best.len() - 1].0 {
+        let pos = best.iter().position(|b| b.0 > dst).unwrap_or(best.len());
+        best.insert(pos, (dst, n.edge_index));
+        best.truncate(k);
+    }
+
+    // Search the side with query first, the other only if it can contain closer point
+    let delta = query[n.axis] - descriptors[n.edge_index][n.axis];
+    let (near, far) = if delta < 0.0 {
+        (n.left, n.right)
+    } else {
+        (n.right, n.left)
+    };
+    kd_nearest(nodes, descriptors, near, query, k, best);
+    if best.len() < k || delta * delta < best[best.len() - 1].0 {
+        kd_nearest(nodes, descriptors, far, query, k, best);
+    }
+}
+
+// Fill edge.candidates with k edges whose descriptors are closest to flipped descriptor
+// of the edge. compute_best_diff then compares only with them.
+fn index_candidates(edges: &mut Vec<EdgeInfo>, k: usize) {
+    let (_, max_height) = edges_max_size(edges);
+    let stripe = cmp::max(max_height / DESCRIPTOR_LEN, 1);
+
+    let descriptors: Vec<Vec<f64>> = edges.iter()
+        .map(|e| edge_descriptor(e, stripe, false))
+        .collect();
+    let mut indices: Vec<usize> = (0..edges.len()).collect();
+    let mut nodes = Vec::with_capacity(edges.len());
+    let root = build_kd_tree(&descriptors, &mut indices, 0, &mut nodes);
+
+    for i in 0..edges.len() {
+        let query = edge_descriptor(&edges[i], stripe, true);
+        let mut best = vec![];
+        // Four more because the edge itself and other 3 edges of its piece can be found
+        kd_nearest(&nodes, &descriptors, root, &query, k + 4, &mut best);
+        let piece_no = edges[i].edge_no >> 2;
+        let mut candidates: Vec<usize> = best.iter()
+            .map(|b| b.1)
+            .filter(|j| edges[*j].edge_no >> 2 != piece_no)
+            .collect();
+        candidates.truncate(k);
+        edges[i].candidates = candidates;
+    }
+    println!("indexed {} edges, {} candidates each", edges.len(), k);
 }
 
 // Compute edge.best_diff vector
@@ -1359,6 +3024,19 @@ fn compute_best_diff(i: usize,
 
     let edges_len = edges.len();
 
+    // With descriptor index re-rank just the candidates with exact compare. When more
+    // best edges are wanted then there are candidates all edges are compared.
+    if edges[i].candidates.len() >= num_best {
+        let mut diffs: Vec<(usize, usize)> = edges[i].candidates
+            .iter()
+            .map(|j| (*j, compare_edges(edges, i, *j)))
+            .collect();
+        diffs.sort_by_key(|a| a.1);
+        diffs.truncate(num_best);
+        edges[i].best_diff = diffs;
+        return;
+    }
+
     // Compare self with all other edges
     compare_edge_with_others(&mut edges, i, max_width, max_height);
 
@@ -1449,60 +3127,1415 @@ fn get_best_diff(e_index: usize,
     let a_no = edges[a].edge_no;
     let a_solved = edges[a].solved_index;
 
-    // Skip solved edges and edges of the same piece
-    if (a_solved != usize::max_value() && a_solved != e_index) || e_no == a_no {
-        return (a, a_no, 100000000);
+    // Skip solved edges, edges of the same piece and edges rejected by user
+    if (a_solved != usize::max_value() && a_solved != e_index) || e_no == a_no ||
+       edges[e_index].rejected.contains(&a) {
+        return (a, a_no, SKIP_PENALTY);
     }
 
     return (a, a_no, diff_a);
 }
 
-// Return next side of the piece
-fn side_plus(edge_no: usize) -> usize {
-    return (edge_no & !3) | ((edge_no + 1) & 3);
+// Print one leg of quad, level is 0 for the first
+fn print_leg(level: usize, i_no: usize, j_no: usize, diff: usize) {
+    println!("{:indent$}{:>6}->{:>6}{:pad$} {:>12}",
+             "",
+             edge_name(i_no),
+             edge_name(j_no),
+             "",
+             diff,
+             indent = 8 * level,
+             pad = 16 - 8 * level);
 }
 
-// Return prev side of the piece
-fn side_minus(edge_no: usize) -> usize {
-    return (edge_no & !3) | ((edge_no + 3) & 3);
-}
+// Diff of the leg closing the quad. It is not taken from best_diff so it must be checked
+// that it's not the same edge, rejected or solved to other edge. Quad counts closing leg
+// twice (weight 2), weight 1 is the same as diffs in best_diff.
+fn closing_diff(edges: &Vec<EdgeInfo>, i: usize, j: usize, weight: usize) -> usize {
+    let i_no = edges[i].edge_no;
+    let j_no = edges[j].edge_no;
+    let mut diff = weight * compare_edges(edges, i, j);
+
+    // Check if it's not the same edge
+    if i == j {
+        println!("SKIP {} and {} is same edge", edge_name(i_no), edge_name(j_no));
+        diff += SKIP_PENALTY;
+    }
 
-// Make file processed
-fn write_done_file(path: &str) {
-    let done_str = path.to_string() + ".done";
-    println!("writting done file {}", done_str);
-    let done_path = Path::new(&done_str);
-    match File::create(&done_path) {
-        Err(why) => {
-            panic!("couldn't create {}: {}",
-                   done_path.display(),
-                   why.description())
-        }
-        Ok(file) => file,
-    };
-}
+    // Check if user did not reject i->j
+    if edges[j].rejected.contains(&i) {
+        println!("SKIP {} was rejected for {}", edge_name(i_no), edge_name(j_no));
+        diff += SKIP_PENALTY;
+    }
 
-// Is file already processed?
-fn is_done(path: &str) -> bool {
-    let done_str = path.to_string() + ".done";
-    let done_path = Path::new(&done_str);
-    if !done_path.exists() {
-        return false;
+    // Check if solved i->j match
+    let i_solved_index = edges[i].solved_index;
+    if i_solved_index != usize::max_value() {
+        diff = if i_solved_index == j {
+            0
+        } else {
+            println!("SKIP {} is already solved to {} and does not match {}",
+                     edge_name(i_no),
+                     edge_name(edges[i_solved_index].edge_no),
+                     edge_name(j_no));
+            diff + SKIP_PENALTY
+        }
     }
-    println!("skipping {} because {} exists", path, done_path.display());
+    let j_solved_index = edges[j].solved_index;
+    if j_solved_index != usize::max_value() {
+        diff = if j_solved_index == i {
+            if diff == 0 {
+                0
+            } else {
+                panic!("{} solved to {} but {} solved to other",
+                       edge_name(j_no),
+                       edge_name(i_no),
+                       edge_name(i_no));
+            }
+        } else {
+            println!("SKIP {} is already solved to {} and does not match {}",
+                     edge_name(j_no),
+                     edge_name(edges[j_solved_index].edge_no),
+                     edge_name(i_no));
+            diff + SKIP_PENALTY
+        }
+    }
+    diff
+}
 
-    return true;
+// Pieces arranged in block by match_block
+struct Block {
+    placements: HashMap<usize, Placement>, // by piece_no, cluster is order of placing
+    pairs: Vec<(usize, usize, usize)>, // all internal (edge_index, edge_index, diff)
+    score: usize,
 }
 
-fn main() {
-    let sdl_context = sdl2::init().unwrap();
+// Block searched by match_block
+#[derive(Copy, Clone)]
+struct BlockParams {
+    start: (isize, isize), // cell of the given piece
+    size: (isize, isize), // cols x rows
+    num_candidates: usize, // tried for each cell
+    beam: usize, // best partial blocks kept
+}
 
-    let mut display_state = DisplayPixelState { autorotate: false };
+// Find the best arrangements of cols x rows pieces with piece_no at start cell with
+// given rotation. Cells are filled from the piece outward, for each cell the candidates
+// of the edge facing it are tried and every internal edge pair is scored, so the
+// arrangement must fit on all sides. Keeps beam best partial blocks.
+fn match_block(edges: &mut Vec<EdgeInfo>,
+               edge_nums: &HashMap<usize, usize>,
+               piece_no: usize,
+               rotation: usize,
+               params: &BlockParams,
+               max_width: usize,
+               max_height: usize)
+               -> Vec<Block> {
+    let BlockParams { start, size, num_candidates, beam } = *params;
+
+    // Cells in order from the start cell, each with already filled neighbour
+    let mut order = vec![(start, start, 0)];
+    let mut k = 0;
+    while k < order.len() {
+        let (cell, _, _) = order[k];
+        for dir in 0..4 {
+            let delta = dir_delta(dir);
+            let next = (cell.0 + delta.0, cell.1 + delta.1);
+            if next.0 < 0 || next.1 < 0 || next.0 >= size.0 || next.1 >= size.1 ||
+               order.iter().any(|o| o.0 == next) {
+                continue;
+            }
+            order.push((next, cell, dir));
+        }
+        k += 1;
+    }
 
-    // Process all .jpg files
-    let entries = fs::read_dir("./jpg").unwrap();
-    for entry in entries {
-        //println!("Name: {}", path.unwrap().path().into_os_string().into_string());
+    let mut first = Block {
+        placements: HashMap::new(),
+        pairs: vec![],
+        score: 0,
+    };
+    first.placements.insert(piece_no,
+                            Placement {
+                                x: start.0,
+                                y: start.1,
+                                rotation,
+                                cluster: 0,
+                            });
+    let mut blocks = vec![first];
+
+    for (k, &(cell, from, dir)) in order.iter().enumerate().skip(1) {
+        let mut next_blocks = vec![];
+        for block in blocks.iter() {
+            let (&from_no, from_p) = block.placements
+                .iter()
+                .find(|&(_, p)| p.x == from.0 && p.y == from.1)
+                .unwrap();
+            let e_index = match edge_nums.get(&(4 * from_no + ((from_p.rotation + dir) & 3))) {
+                Some(e_index) => *e_index,
+                None => continue,
+            };
+
+            // Solved edge has just one candidate
+            let num_nth = if edges[e_index].solved_index != usize::max_value() {
+                1
+            } else {
+                num_candidates
+            };
+            for nth in 0..num_nth {
+                let (f, f_no, diff) =
+                    get_best_diff(e_index, edges, nth, num_candidates, max_width, max_height);
+                let q_no = f_no >> 2;
+                if diff >= SKIP_PENALTY || block.placements.contains_key(&q_no) {
+                    continue;
+                }
+
+                // Neighbour edge must face back, see place_pieces
+                let q = Placement {
+                    x: cell.0,
+                    y: cell.1,
+                    rotation: ((f_no & 3) + 4 - ((dir + 2) & 3)) & 3,
+                    cluster: k,
+                };
+                let mut pairs = block.pairs.clone();
+                pairs.push((e_index, f, diff));
+                let mut score = block.score + diff;
+
+                // Score edges to the other filled neighbours
+                for d in 0..4 {
+                    let delta = dir_delta(d);
+                    let n_cell = (cell.0 + delta.0, cell.1 + delta.1);
+                    if n_cell == from {
+                        continue;
+                    }
+                    let (&n_no, n_p) = match block.placements
+                        .iter()
+                        .find(|&(_, p)| p.x == n_cell.0 && p.y == n_cell.1) {
+                        Some(n) => n,
+                        None => continue,
+                    };
+                    let i_no = 4 * q_no + ((q.rotation + d) & 3);
+                    let j_no = 4 * n_no + ((n_p.rotation + d + 2) & 3);
+                    let (i, j) = match (edge_nums.get(&i_no), edge_nums.get(&j_no)) {
+                        (Some(i), Some(j)) => (*i, *j),
+                        _ => continue,
+                    };
+                    let diff = closing_diff(edges, i, j, 1);
+                    pairs.push((i, j, diff));
+                    score += diff;
+                }
+
+                let mut placements = block.placements.clone();
+                placements.insert(q_no, q);
+                next_blocks.push(Block {
+                    placements,
+                    pairs,
+                    score,
+                });
+            }
+        }
+        next_blocks.sort_by_key(|a| a.score);
+        next_blocks.truncate(beam);
+        blocks = next_blocks;
+    }
+    blocks
+}
+
+// Return next side of the piece
+fn side_plus(edge_no: usize) -> usize {
+    return (edge_no & !3) | ((edge_no + 1) & 3);
+}
+
+// Return prev side of the piece
+fn side_minus(edge_no: usize) -> usize {
+    return (edge_no & !3) | ((edge_no + 3) & 3);
+}
+
+// Edge name as used in txt files, e.g. 12.3
+fn edge_name(edge_no: usize) -> String {
+    format!("{}.{}", edge_no >> 2, edge_no & 3)
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+// Lines for solved_edges.txt, one "a.s,b.t,source,diff,timestamp,session" line per pair
+fn solved_lines(edges: &Vec<EdgeInfo>, step: &SolvedStep) -> String {
+    let mut res = String::new();
+    for &(i, j) in step.pairs.iter() {
+        res += &format!("{},{},{},{},{},{}\n",
+                        edge_name(edges[i].edge_no),
+                        edge_name(edges[j].edge_no),
+                        step.source,
+                        compare_edges(edges, i, j),
+                        step.timestamp,
+                        step.session);
+    }
+    res
+}
+
+fn apply_solved(edges: &mut Vec<EdgeInfo>, step: &SolvedStep) {
+    for &(i, j) in step.pairs.iter() {
+        edges[i].solved_index = j;
+        edges[j].solved_index = i;
+        edges[i].solved_source = step.source.to_string();
+        edges[j].solved_source = step.source.to_string();
+    }
+}
+
+fn unapply_solved(edges: &mut Vec<EdgeInfo>, step: &SolvedStep) {
+    for &(i, j) in step.pairs.iter() {
+        edges[i].solved_index = usize::max_value();
+        edges[j].solved_index = usize::max_value();
+        edges[i].solved_source.clear();
+        edges[j].solved_source.clear();
+
+        // best_diff of solved edge is just (solved edge, 0), compute it again
+        edges[i].best_diff.clear();
+        edges[j].best_diff.clear();
+    }
+}
+
+fn append_to_file(path: &str, content: &str) {
+    let mut file = OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(path)
+        .unwrap();
+
+    if let Err(e) = file.write_all(content.as_bytes()) {
+        println!("{}", e);
+    } else {
+        println!("written to {}", path);
+    }
+}
+
+// Remove lines previously added by append_to_file
+fn remove_solved_lines(path: &str, content: &str) {
+    let mut text = String::new();
+    match File::open(path) {
+        Err(why) => panic!("couldn't open {}: {}", path, why),
+        Ok(mut file) => file.read_to_string(&mut text).unwrap(),
+    };
+
+    let mut lines: Vec<&str> = text.lines().collect();
+
+    // Remove the last occurrence of each line, lines can be hand edited meanwhile
+    for line in content.lines().rev() {
+        match lines.iter().rposition(|l| l.trim() == line) {
+            Some(pos) => {
+                lines.remove(pos);
+            }
+            None => println!("{} not found in {}", line, path),
+        }
+    }
+
+    let mut res = lines.join("\n");
+    if !lines.is_empty() {
+        res += "\n";
+    }
+
+    let mut file = match File::create(path) {
+        Err(why) => panic!("couldn't create {}: {}", path, why),
+        Ok(file) => file,
+    };
+    match file.write_all(res.as_bytes()) {
+        Err(why) => panic!("couldn't write to {}: {}", path, why),
+        Ok(_) => println!("removed {} lines from {}", content.lines().count(), path),
+    }
+}
+
+// File that belongs to solved file, e.g. history or rejected edges, in its directory
+fn solved_file_sibling(solved_file: &str, file_name: &str) -> String {
+    Path::new(solved_file).with_file_name(file_name).to_str().unwrap().to_string()
+}
+
+// Log solve/undo/redo decision with timestamp, edges and score
+fn write_history(history_file: &str, action: &str, edges: &Vec<EdgeInfo>, step: &SolvedStep) {
+    let mut line = format!("{} {:<5}", unix_time(), action);
+    for &(i, j) in step.pairs.iter() {
+        line += &format!(" {},{}", edge_name(edges[i].edge_no), edge_name(edges[j].edge_no));
+    }
+    line += &format!(" score={}\n", step.score);
+    append_to_file(history_file, &line);
+}
+
+// Read edges and pieces txt files from dir. Returns edges, pieces points by piece_no and
+// hashmap to get edge index by edge_no.
+fn read_edges(dir: &str,
+              config: &HashMap<String, String>)
+              -> (Vec<EdgeInfo>, Pieces, HashMap<usize, usize>) {
+
+    let mut edges = vec![];
+    let mut pieces = HashMap::new();
+    let entries = fs::read_dir(dir).unwrap();
+    for entry in entries {
+
+        let path = entry.unwrap().path();
+        if path.extension().and_then(OsStr::to_str) != Some("txt") {
+            continue;
+        }
+
+        let (edge_no, piece_no) = {
+
+            let file_stem = path.file_stem()
+                .unwrap()
+                .to_str()
+                .unwrap();
+
+            // Other txt files, e.g. notes, are skipped
+            let filename_nums: usize = match file_stem.replace(".", "").parse() {
+                Ok(nums) => nums,
+                Err(_) => {
+                    println!("skipping {}, not piece or edge", path.display());
+                    continue;
+                }
+            };
+
+            if file_stem.contains(".") {
+                // edge no: 12.3.txt -> 123 -> 4 * 12 + 3
+                (4 * (filename_nums / 10) + (filename_nums % 10), usize::max_value())
+            } else {
+                (usize::max_value(), filename_nums) // piece_no
+            }
+        };
+
+        let path_str = path.into_os_string().into_string().unwrap();
+        let mut points = read_txt(&path_str);
+
+        // Pieces scanned with different resolution must be resized to the same scale
+        let factor = if piece_no != usize::max_value() {
+            scale_factor(config, dir, piece_no)
+        } else {
+            scale_factor(config, dir, edge_no >> 2)
+        };
+        if (factor - 1.0).abs() > 0.001 {
+            points = scale_points(&points, factor);
+        }
+
+        // If it's pieces, just read points
+        if piece_no != usize::max_value() {
+            pieces.insert(piece_no, points);
+            continue;
+        }
+
+        // It's edge. Compute height and add EdgeInfo
+        let mut max_x = 0;
+        let mut max_y = 0;
+        for p in points.iter() {
+            max_x = cmp::max(max_x, p.0);
+            max_y = cmp::max(max_y, p.1);
+        }
+
+        let edge_info = EdgeInfo {
+            points,
+            edge_no,
+            max_x,
+            max_y,
+            diff_to: vec![],
+            best_diff: vec![],
+            edge_index: usize::max_value(),
+            solved_index: usize::max_value(),
+            rejected: vec![],
+            candidates: vec![],
+            solved_source: String::new(),
+        };
+        edges.push(edge_info);
+    }
+
+    // Hashmap to get index by edge_no
+    let mut edge_nums = HashMap::new();
+    for (i, edge_i) in edges.iter_mut().enumerate() {
+        let i_no = edge_i.edge_no;
+        //println!("edge={}.{}", i_no >> 2, i_no & 3);
+        edge_nums.insert(i_no, i);
+        edge_i.edge_index = i;
+    }
+
+    // Compare each edge only with partners found in descriptor index
+    if let Some(k) = config_usize(config, "index_candidates") {
+        index_candidates(&mut edges, k);
+    }
+
+    (edges, pieces, edge_nums)
+}
+
+// Read edges and outline of re-extracted piece again, invalidate diffs to them and
+// rebuild candidates index. Edges bigger then max_size (width, height) do not fit solver
+// buffers, returns false.
+fn reload_piece(edges: &mut Vec<EdgeInfo>,
+                pieces: &mut Pieces,
+                edge_nums: &HashMap<usize, usize>,
+                dir: &str,
+                piece_no: usize,
+                max_size: (usize, usize),
+                config: &HashMap<String, String>)
+                -> bool {
+    let (max_width, max_height) = max_size;
+    let factor = scale_factor(config, dir, piece_no);
+    let read_scaled = |path: &str| {
+        let points = read_txt(path);
+        if (factor - 1.0).abs() > 0.001 { scale_points(&points, factor) } else { points }
+    };
+
+    let mut side_points = vec![];
+    for side in 0..4 {
+        let path = format!("{}/{}.{}.txt", dir, piece_no, side);
+        let index = match edge_nums.get(&(4 * piece_no + side)) {
+            Some(index) if Path::new(&path).exists() => *index,
+            _ => {
+                println!("{} not found, restart solver to add new edges", path);
+                return false;
+            }
+        };
+        let points = read_scaled(&path);
+        let max_x = points.iter().map(|p| p.0).max().unwrap_or(0);
+        let max_y = points.iter().map(|p| p.1).max().unwrap_or(0);
+        if max_x >= max_width || max_y >= max_height {
+            println!("{} is {}x{}, bigger then {}x{}, restart solver to use it",
+                     path,
+                     max_x,
+                     max_y,
+                     max_width,
+                     max_height);
+            return false;
+        }
+        side_points.push((index, points, max_x, max_y));
+    }
+
+    let mut reloaded = vec![];
+    for (index, points, max_x, max_y) in side_points {
+        let edge = &mut edges[index];
+        edge.points = points;
+        edge.max_x = max_x;
+        edge.max_y = max_y;
+        edge.diff_to.clear();
+        edge.best_diff.clear();
+        edge.candidates.clear();
+        reloaded.push(index);
+    }
+    pieces.insert(piece_no, read_scaled(&format!("{}/{}.txt", dir, piece_no)));
+
+    // Precomputed diffs of other edges to reloaded ones are recomputed, best
+    // candidates of all edges may change
+    for i in 0..edges.len() {
+        if !edges[i].diff_to.is_empty() {
+            for &j in reloaded.iter() {
+                edges[i].diff_to[j] = squared_dst_one_way(edges, i, j).0;
+            }
+        }
+        edges[i].best_diff.clear();
+    }
+
+    // Descriptors of reloaded edges changed, so index of all edges is built again
+    if let Some(k) = config_usize(config, "index_candidates") {
+        index_candidates(edges, k);
+    }
+    println!("piece {} reloaded", piece_no);
+    true
+}
+
+// Read "12.3,45.0" lines (e.g. solved_edges.txt) and return pairs of edge indices
+fn read_edge_pairs(txt_file: &str, edge_nums: &HashMap<usize, usize>) -> Vec<(usize, usize)> {
+    read_edge_lines(txt_file, edge_nums).into_iter().map(|l| (l.0, l.1)).collect()
+}
+
+// Read "12.3,45.0,field..." lines and return pairs of edge indices with the other fields,
+// e.g. source,diff,timestamp,session in solved_edges.txt
+fn read_edge_lines(txt_file: &str,
+                   edge_nums: &HashMap<usize, usize>)
+                   -> Vec<(usize, usize, Vec<String>)> {
+    let mut text = String::new();
+    match File::open(txt_file) {
+        Err(why) => panic!("couldn't open {}: {}", txt_file, why),
+        Ok(mut file) => file.read_to_string(&mut text).unwrap(),
+    };
+
+    let mut res = vec![];
+    for line in text.lines() {
+        let v: Vec<&str> = line.split(',').collect();
+        if v.len() < 2 {
+            continue;
+        }
+        let p: (usize, usize) = (v[0].trim().replace(".", "").parse().unwrap(),
+                                 v[1].trim().replace(".", "").parse().unwrap());
+        let i_no = 4 * (p.0 / 10) + (p.0 % 10); // edge no: 12.3 -> 123 -> 4 * 12 + 3
+        let j_no = 4 * (p.1 / 10) + (p.1 % 10);
+        match (edge_nums.get(&i_no), edge_nums.get(&j_no)) {
+            (Some(i_index), Some(j_index)) => {
+                let fields = v[2..].iter().map(|f| f.trim().to_string()).collect();
+                res.push((*i_index, *j_index, fields))
+            }
+            _ => {
+                println!("{}: skipping {},{}, edge not found",
+                         txt_file,
+                         edge_name(i_no),
+                         edge_name(j_no))
+            }
+        }
+    }
+    res
+}
+
+// Print clusters of pieces connected by solved edges with their grid positions
+fn print_clusters(data_dir: &str, solved_file: &str, config: &HashMap<String, String>) {
+    let (mut edges, pieces, edge_nums) = read_edges(data_dir, config);
+
+    // Edge has just one solved_index, so edges paired more times are found here
+    let mut partners: HashMap<usize, Vec<usize>> = HashMap::new();
+    for (i, j) in read_edge_pairs(solved_file, &edge_nums) {
+        edges[i].solved_index = j;
+        edges[j].solved_index = i;
+        partners.entry(i).or_insert(vec![]).push(j);
+        partners.entry(j).or_insert(vec![]).push(i);
+    }
+    let mut twice: Vec<(&usize, &Vec<usize>)> = partners.iter().filter(|p| p.1.len() > 1).collect();
+    twice.sort();
+
+    let (placements, mut contradictions) = place_pieces(&edges, &edge_nums);
+    for (k, &(i, js)) in twice.iter().enumerate() {
+        let names: Vec<String> = js.iter().map(|j| edge_name(edges[*j].edge_no)).collect();
+        contradictions.insert(k,
+                              format!("edge {} paired with {}",
+                                      edge_name(edges[*i].edge_no),
+                                      names.join(", ")));
+    }
+
+    // Pieces of each cluster sorted by position
+    let mut clusters: Vec<Vec<(usize, Placement)>> = vec![];
+    for (piece_no, p) in placements.iter() {
+        while clusters.len() <= p.cluster {
+            clusters.push(vec![]);
+        }
+        clusters[p.cluster].push((*piece_no, *p));
+    }
+    clusters.sort_by_key(|c| cmp::Reverse(c.len()));
+
+    println!();
+    println!("{} clusters, {} pieces placed", clusters.len(), placements.len());
+    for (i, cluster) in clusters.iter_mut().enumerate() {
+        cluster.sort_by_key(|a| (a.1.y, a.1.x));
+        print!("cluster {:>3} size {:>4}:", i, cluster.len());
+        for &(piece_no, p) in cluster.iter() {
+            print!(" {}@{},{}r{}", piece_no, p.x, p.y, p.rotation);
+        }
+        println!();
+    }
+
+    let mut unplaced: Vec<usize> = pieces.keys()
+        .filter(|p| !placements.contains_key(p))
+        .cloned()
+        .collect();
+    unplaced.sort();
+    println!("{} unplaced pieces: {:?}", unplaced.len(), unplaced);
+
+    println!("{} contradictions", contradictions.len());
+    for c in contradictions.iter() {
+        println!("  {}", c);
+    }
+}
+
+// Solved partner edge_no of edge_no or None
+fn solved_partner(edges: &Vec<EdgeInfo>,
+                  edge_nums: &HashMap<usize, usize>,
+                  edge_no: usize)
+                  -> Option<usize> {
+    match edge_nums.get(&edge_no) {
+        Some(i) if edges[*i].solved_index != usize::max_value() => {
+            Some(edges[edges[*i].solved_index].edge_no)
+        }
+        _ => None,
+    }
+}
+
+// Check that each 2x2 block implied by solved edges closes:
+//
+//     C  ->  D
+//     ^      |
+//     |      v
+//     B  <-  A
+//
+// Starting from solved A->B, following side_plus and solved partners around the
+// corner must come back to side_minus(A). Returns number of closed blocks and edge_no
+// pairs of blocks that do not close.
+fn check_cycles(edges: &Vec<EdgeInfo>,
+                edge_nums: &HashMap<usize, usize>)
+                -> (usize, Vec<Vec<(usize, usize)>>) {
+
+    let mut closed = 0;
+    let mut violations: Vec<Vec<(usize, usize)>> = vec![];
+
+    for edge in edges.iter() {
+        if edge.solved_index == usize::max_value() {
+            continue;
+        }
+        let a_no = edge.edge_no;
+        let b_no = edges[edge.solved_index].edge_no;
+        let c_no = match solved_partner(edges, edge_nums, side_plus(b_no)) {
+            Some(c_no) => c_no,
+            None => continue,
+        };
+        let d_no = match solved_partner(edges, edge_nums, side_plus(c_no)) {
+            Some(d_no) => d_no,
+            None => continue,
+        };
+        let a_minus_no = match solved_partner(edges, edge_nums, side_plus(d_no)) {
+            Some(a_minus_no) => a_minus_no,
+            None => continue, // block not complete yet
+        };
+
+        // The 4 pieces must be different and the loop must close
+        let mut block_pieces = vec![a_no >> 2, b_no >> 2, c_no >> 2, d_no >> 2];
+        block_pieces.sort();
+        block_pieces.dedup();
+        if a_minus_no == side_minus(a_no) && block_pieces.len() == 4 {
+            closed += 1;
+            continue;
+        }
+
+        // Each block is found from all its pairs, keep it once
+        let mut pairs = vec![(a_no, b_no),
+                             (side_plus(b_no), c_no),
+                             (side_plus(c_no), d_no),
+                             (side_plus(d_no), a_minus_no)];
+        for pair in pairs.iter_mut() {
+            if pair.0 > pair.1 {
+                *pair = (pair.1, pair.0);
+            }
+        }
+        pairs.sort();
+        if !violations.contains(&pairs) {
+            violations.push(pairs);
+        }
+    }
+
+    // Each closed block was counted from its 4 pairs
+    (closed / 4, violations)
+}
+
+// Print 2x2 blocks of solved edges that do not close and pairs found in most of them
+fn print_check(data_dir: &str, solved_file: &str, config: &HashMap<String, String>) {
+    let (mut edges, _, edge_nums) = read_edges(data_dir, config);
+    for (i, j) in read_edge_pairs(solved_file, &edge_nums) {
+        edges[i].solved_index = j;
+        edges[j].solved_index = i;
+    }
+
+    let (closed, violations) = check_cycles(&edges, &edge_nums);
+
+    println!();
+    println!("{} closed 2x2 blocks, {} blocks do not close", closed, violations.len());
+
+    let mut pair_counts: HashMap<(usize, usize), usize> = HashMap::new();
+    for block in violations.iter() {
+        print!("  block");
+        for pair in block.iter() {
+            print!(" {},{}", edge_name(pair.0), edge_name(pair.1));
+            *pair_counts.entry(*pair).or_insert(0) += 1;
+        }
+        println!();
+    }
+
+    // Pairs in more open blocks are more likely to be mistake
+    let mut pairs: Vec<((usize, usize), usize)> = pair_counts.into_iter().collect();
+    pairs.sort_by(|a, b| (b.1, a.0).cmp(&(a.1, b.0)));
+    if !pairs.is_empty() {
+        println!("likely wrong pairs (number of blocks that do not close):");
+    }
+    for &(pair, count) in pairs.iter() {
+        println!("  {},{} {}", edge_name(pair.0), edge_name(pair.1), count);
+    }
+}
+
+fn write_text_file(path: &str, content: &str) {
+    let mut file = match File::create(path) {
+        Err(why) => panic!("couldn't create {}: {}", path, why),
+        Ok(file) => file,
+    };
+    match file.write_all(content.as_bytes()) {
+        Err(why) => panic!("couldn't write to {}: {}", path, why),
+        Ok(_) => println!("successfully wrote to {}", path),
+    }
+}
+
+// SVG path drawing 1x1 square for each point
+fn svg_points(points: &Vec<(usize, usize)>, left: usize, top: usize, color: &str) -> String {
+    let mut d = String::new();
+    for p in points.iter() {
+        d += &format!("M{},{}h1v1h-1z", p.0 + left, p.1 + top);
+    }
+    format!("<path d=\"{}\" fill=\"{}\"/>\n", d, color)
+}
+
+const SVG_EDGE_COLORS: [&str; 4] = ["red", "green", "blue", "orange"];
+
+// Piece outline with its 4 edges in different colors and circles in corners. Edges are
+// rotated back from the orientation they were saved in and put along matching side of
+// the outline.
+fn piece_svg(edges: &Vec<EdgeInfo>,
+             edge_nums: &HashMap<usize, usize>,
+             piece_no: usize,
+             outline: &Vec<(usize, usize)>)
+             -> String {
+
+    let max = max_xy(outline);
+    let margin = 20;
+    let mut body = svg_points(outline, margin, margin, "gray");
+
+    for (side, &color) in SVG_EDGE_COLORS.iter().enumerate() {
+        let e_index = match edge_nums.get(&(4 * piece_no + side)) {
+            Some(e_index) => *e_index,
+            None => continue,
+        };
+        let edge = &edges[e_index];
+        if edge.points.is_empty() {
+            println!("edge {} is empty, skipping it", edge_name(edge.edge_no));
+            continue;
+        }
+
+        // Edge was saved with side on the left, side 1 is rotated by 90 degrees clockwise
+        let points = rotate_coords(&edge.points, (4 - side) & 3);
+        let corners = rotate_coords(&vec![edge.points[0],
+                                          edge.points[edge.points.len() - 1],
+                                          (edge.max_x, edge.max_y),
+                                          (0, 0)],
+                                    (4 - side) & 3);
+        let e_max = max_xy(&points);
+
+        // 0=left, 1=bottom, 2=right, 3=top
+        let (left, top) = match side {
+            0 => (0, (max.1 - cmp::min(max.1, e_max.1)) / 2),
+            1 => ((max.0 - cmp::min(max.0, e_max.0)) / 2, max.1 - cmp::min(max.1, e_max.1)),
+            2 => (max.0 - cmp::min(max.0, e_max.0), (max.1 - cmp::min(max.1, e_max.1)) / 2),
+            _ => ((max.0 - cmp::min(max.0, e_max.0)) / 2, 0),
+        };
+
+        body += &svg_points(&points, left + margin, top + margin, color);
+        for c in corners[0..2].iter() {
+            body += &format!("<circle cx=\"{}\" cy=\"{}\" r=\"4\" fill=\"none\" \
+                              stroke=\"black\"/>\n",
+                             c.0 + left + margin,
+                             c.1 + top + margin);
+        }
+        body += &format!("<text x=\"{}\" y=\"{}\" fill=\"{}\">{}</text>\n",
+                         left + margin + e_max.0 / 2,
+                         top + margin + e_max.1 / 2,
+                         color,
+                         edge_name(edge.edge_no));
+    }
+
+    format!("<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\">\n{}</svg>\n",
+            max.0 + 2 * margin,
+            max.1 + 2 * margin,
+            body)
+}
+
+// All placed pieces of solved clusters with piece numbers
+fn layout_svg(edges: &Vec<EdgeInfo>,
+              pieces: &Pieces,
+              placements: &HashMap<usize, Placement>)
+              -> String {
+
+    // Cell is average edge length
+    let mut len_sum = 0;
+    for edge in edges.iter() {
+        len_sum += edge.max_y;
+    }
+    let cell = cmp::max(len_sum / cmp::max(edges.len(), 1), 1) as isize;
+
+    // Clusters are put below each other
+    let mut min_x = 0;
+    let mut max_x = 0;
+    let mut cluster_rows: Vec<(isize, isize)> = vec![];
+    for p in placements.values() {
+        while cluster_rows.len() <= p.cluster {
+            cluster_rows.push((isize::max_value(), isize::min_value()));
+        }
+        let rows = &mut cluster_rows[p.cluster];
+        rows.0 = cmp::min(rows.0, p.y);
+        rows.1 = cmp::max(rows.1, p.y);
+        min_x = cmp::min(min_x, p.x);
+        max_x = cmp::max(max_x, p.x);
+    }
+    let mut cluster_top = vec![];
+    let mut rows_sum = 0;
+    for rows in cluster_rows.iter() {
+        cluster_top.push(rows_sum - rows.0);
+        rows_sum += rows.1 - rows.0 + 2;
+    }
+
+    let mut body = String::new();
+    for (piece_no, p) in placements.iter() {
+        let points = match pieces.get(piece_no) {
+            Some(points) => rotate_coords(points, p.rotation),
+            None => continue,
+        };
+        let max = max_xy(&points);
+
+        // Center piece in its cell
+        let cx = (p.x - min_x + 1) * cell + cell / 2;
+        let cy = (p.y + cluster_top[p.cluster] + 1) * cell + cell / 2;
+        let left = cmp::max(cx - (max.0 / 2) as isize, 0) as usize;
+        let top = cmp::max(cy - (max.1 / 2) as isize, 0) as usize;
+
+        body += &svg_points(&points, left, top, SVG_EDGE_COLORS[p.cluster % 4]);
+        body += &format!("<text x=\"{}\" y=\"{}\">{}</text>\n", cx, cy, piece_no);
+    }
+
+    format!("<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\">\n{}</svg>\n",
+            (max_x - min_x + 3) * cell,
+            (rows_sum + 2) * cell,
+            body)
+}
+
+// Write data/N.svg for given pieces (all if empty) and layout.svg with solved clusters
+fn export_svg(data_dir: &str,
+              solved_file: &str,
+              piece_nums: &Vec<usize>,
+              layout: bool,
+              config: &HashMap<String, String>) {
+    let (mut edges, pieces, edge_nums) = read_edges(data_dir, config);
+
+    let mut piece_nums = piece_nums.clone();
+    if piece_nums.is_empty() {
+        piece_nums = pieces.keys().cloned().collect();
+        piece_nums.sort();
+    }
+    for piece_no in piece_nums {
+        let outline = match pieces.get(&piece_no) {
+            Some(outline) => outline,
+            None => {
+                println!("piece {} not found", piece_no);
+                continue;
+            }
+        };
+        let svg = piece_svg(&edges, &edge_nums, piece_no, outline);
+        write_text_file(&format!("{}/{}.svg", data_dir, piece_no), &svg);
+    }
+
+    if layout {
+        for (i, j) in read_edge_pairs(solved_file, &edge_nums) {
+            edges[i].solved_index = j;
+            edges[j].solved_index = i;
+        }
+        let (placements, _) = place_pieces(&edges, &edge_nums);
+        write_text_file("layout.svg", &layout_svg(&edges, &pieces, &placements));
+    }
+}
+
+// Piece rotation for one side as found by process_png
+struct PieceSide {
+    angle: f64, // degrees clockwise, side is on the left after rotation
+    top: (f64, f64), // top-left corner in png coordinates
+    bot: (f64, f64), // bottom-left corner in png coordinates
+}
+
+// Point in rotated picture back to png coordinates. Png was drawn at shift,shift and
+// rotated around its center (see rotate_and_find_corners).
+fn unrotate_point(x: usize,
+                  y: usize,
+                  angle: f64,
+                  shift: usize,
+                  width: usize,
+                  height: usize)
+                  -> (f64, f64) {
+    let (sin, cos) = angle.to_radians().sin_cos();
+    let dx = x as f64 - (shift as f64 + width as f64 / 2.0);
+    let dy = y as f64 - (shift as f64 + height as f64 / 2.0);
+    (width as f64 / 2.0 + dx * cos + dy * sin, height as f64 / 2.0 - dx * sin + dy * cos)
+}
+
+// Png coordinates to rotated picture, inverse of unrotate_point
+fn rotate_point(x: f64,
+                y: f64,
+                angle: f64,
+                shift: usize,
+                width: usize,
+                height: usize)
+                -> (f64, f64) {
+    let (sin, cos) = angle.to_radians().sin_cos();
+    let sx = x - width as f64 / 2.0;
+    let sy = y - height as f64 / 2.0;
+    (shift as f64 + width as f64 / 2.0 + sx * cos - sy * sin,
+     shift as f64 + height as f64 / 2.0 + sx * sin + sy * cos)
+}
+
+// Read N.png.corners with four corners clicked by user in png coordinates
+fn read_corners(png_file: &str) -> Option<Vec<(f64, f64)>> {
+    let corners_file = format!("{}.corners", png_file);
+    if !Path::new(&corners_file).exists() {
+        return None;
+    }
+    let points = read_txt(&corners_file);
+    if points.len() != 4 {
+        println!("{} must have 4 corners, ignoring it", corners_file);
+        return None;
+    }
+    Some(points.iter().map(|p| (p.0 as f64, p.1 as f64)).collect())
+}
+
+// Angle and top,bottom corners in rotated picture for side given by corners clicked by
+// user. The side is made of two corners on the left and angle makes it vertical.
+fn corners_side(corners: &Vec<(f64, f64)>,
+                side: usize,
+                shift: usize,
+                width: usize,
+                height: usize)
+                -> (f64, (usize, usize), (usize, usize)) {
+    let rotated = |angle: f64| -> ((f64, f64), (f64, f64)) {
+        let mut points: Vec<(f64, f64)> = corners.iter()
+            .map(|c| rotate_point(c.0, c.1, angle, shift, width, height))
+            .collect();
+        points.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+        if points[0].1 < points[1].1 {
+            (points[0], points[1])
+        } else {
+            (points[1], points[0])
+        }
+    };
+
+    let base = (90 * side) as f64;
+    let (top, bot) = rotated(base);
+    let angle = base + (bot.0 - top.0).atan2(bot.1 - top.1).to_degrees();
+    let (top, bot) = rotated(angle);
+    (angle,
+     (top.0.round() as usize, top.1.round() as usize),
+     (bot.0.round() as usize, bot.1.round() as usize))
+}
+
+// Read N.png.sides written by process_png
+fn read_piece_sides(png_file: &str) -> Option<Vec<PieceSide>> {
+    let mut content = String::new();
+    match File::open(format!("{}.sides", png_file)) {
+        Err(_) => return None,
+        Ok(mut file) => file.read_to_string(&mut content).unwrap(),
+    };
+
+    let mut sides = vec![];
+    for line in content.lines() {
+        let v: Vec<&str> = line.split(',').collect();
+        if v.len() != 9 {
+            continue;
+        }
+        let angle = f64::from_str(v[1]).unwrap();
+        let n: Vec<usize> = v[2..].iter().map(|x| usize::from_str(x).unwrap()).collect();
+        sides.push(PieceSide {
+            angle,
+            top: unrotate_point(n[0], n[1], angle, n[4], n[5], n[6]),
+            bot: unrotate_point(n[2], n[3], angle, n[4], n[5], n[6]),
+        });
+    }
+    if sides.len() != 4 {
+        return None;
+    }
+    Some(sides)
+}
+
+// Read N.png.crop written by process_jpg: jpg file and rectangle of piece in it
+fn read_crop(png_file: &str) -> Option<(String, u32, u32, u32, u32)> {
+    let mut content = String::new();
+    match File::open(format!("{}.crop", png_file)) {
+        Err(_) => return None,
+        Ok(mut file) => file.read_to_string(&mut content).unwrap(),
+    };
+    let v: Vec<&str> = content.trim().split(',').collect();
+    if v.len() != 5 {
+        return None;
+    }
+    let n: Vec<u32> = v[1..].iter().map(|x| u32::from_str(x).unwrap()).collect();
+    Some((v[0].to_string(), n[0], n[1], n[2], n[3]))
+}
+
+// Compose picture of solved clusters from original jpg colors. Each piece is rotated
+// so that the side on the left in its placement is vertical and the center of its 4
+// corners is put in the center of its grid cell.
+fn render_assembled(data_dir: &str,
+                    solved_file: &str,
+                    out_file: &str,
+                    config: &HashMap<String, String>) {
+    let (mut edges, _, edge_nums) = read_edges(data_dir, config);
+    for (i, j) in read_edge_pairs(solved_file, &edge_nums) {
+        edges[i].solved_index = j;
+        edges[j].solved_index = i;
+    }
+    let (placements, _) = place_pieces(&edges, &edge_nums);
+
+    // Load rotations of placed pieces, cell is average distance between corners
+    let mut piece_sides = HashMap::new();
+    let mut len_sum = 0f64;
+    for piece_no in placements.keys() {
+        let png_file = format!("{}/{}.png", data_dir, piece_no);
+        let factor = scale_factor(config, data_dir, *piece_no);
+        match read_piece_sides(&png_file) {
+            Some(sides) => {
+                for side in sides.iter() {
+                    len_sum += factor *
+                               ((side.bot.0 - side.top.0).powi(2) +
+                                (side.bot.1 - side.top.1).powi(2))
+                        .sqrt();
+                }
+                piece_sides.insert(*piece_no, sides);
+            }
+            None => println!("skipping {}, no {}.sides, extract it again", piece_no, png_file),
+        }
+    }
+    if piece_sides.is_empty() {
+        println!("nothing to render");
+        return;
+    }
+    let cell = (len_sum / (4 * piece_sides.len()) as f64).round() as isize;
+
+    // Clusters are put below each other
+    let mut min_x = 0;
+    let mut max_x = 0;
+    let mut cluster_rows: Vec<(isize, isize)> = vec![];
+    for p in placements.values() {
+        while cluster_rows.len() <= p.cluster {
+            cluster_rows.push((isize::max_value(), isize::min_value()));
+        }
+        let rows = &mut cluster_rows[p.cluster];
+        rows.0 = cmp::min(rows.0, p.y);
+        rows.1 = cmp::max(rows.1, p.y);
+        min_x = cmp::min(min_x, p.x);
+        max_x = cmp::max(max_x, p.x);
+    }
+    let mut cluster_top = vec![];
+    let mut rows_sum = 0;
+    for rows in cluster_rows.iter() {
+        cluster_top.push(rows_sum - rows.0);
+        rows_sum += rows.1 - rows.0 + 2;
+    }
+
+    let out_width = ((max_x - min_x + 3) * cell) as u32;
+    let out_height = ((rows_sum + 2) * cell) as u32;
+    println!("rendering {} pieces into {}x{}", piece_sides.len(), out_width, out_height);
+    let white = image::Rgb([255u8, 255, 255]);
+    let mut out = image::ImageBuffer::from_pixel(out_width, out_height, white);
+
+    let mut jpgs = HashMap::new();
+    for (piece_no, sides) in piece_sides.iter() {
+        let png_file = format!("{}/{}.png", data_dir, piece_no);
+        let (jpg_file, left, top, width, height) = match read_crop(&png_file) {
+            Some(crop) => crop,
+            None => {
+                println!("skipping {}, no {}.crop, cut it from jpg again", piece_no, png_file);
+                continue;
+            }
+        };
+        if !jpgs.contains_key(&jpg_file) {
+            let jpg = image::open(Path::new(&jpg_file)).unwrap().to_rgb();
+            jpgs.insert(jpg_file.clone(), jpg);
+        }
+        let jpg = &jpgs[&jpg_file];
+        let mask = image::open(Path::new(&png_file)).unwrap().to_luma();
+
+        let p = placements[piece_no];
+        let angle = sides[p.rotation].angle;
+        let (sin, cos) = angle.to_radians().sin_cos();
+
+        // Center of piece body in png
+        let mut center = (0f64, 0f64);
+        for side in sides.iter() {
+            center.0 += (side.top.0 + side.bot.0) / 8.0;
+            center.1 += (side.top.1 + side.bot.1) / 8.0;
+        }
+
+        let cx = (p.x - min_x + 1) * cell + cell / 2;
+        let cy = (p.y + cluster_top[p.cluster] + 1) * cell + cell / 2;
+        // Pieces from jpgs with different resolution are resized to the same scale
+        let factor = scale_factor(config, data_dir, *piece_no);
+        let radius = (factor * ((width * width + height * height) as f64).sqrt()) as isize;
+
+        for oy in cmp::max(cy - radius, 0)..cmp::min(cy + radius, out_height as isize) {
+            for ox in cmp::max(cx - radius, 0)..cmp::min(cx + radius, out_width as isize) {
+                // Rotate back to png
+                let dx = (ox - cx) as f64 / factor;
+                let dy = (oy - cy) as f64 / factor;
+                let x = center.0 + dx * cos + dy * sin;
+                let y = center.1 - dx * sin + dy * cos;
+                if x < 0.0 || y < 0.0 || x >= width as f64 || y >= height as f64 {
+                    continue;
+                }
+                let (x, y) = (x as u32, y as u32);
+
+                // Just material, background is white in png
+                if mask.get_pixel(x, y).data[0] > 127 {
+                    continue;
+                }
+                if left + x >= jpg.width() || top + y >= jpg.height() {
+                    continue;
+                }
+                out.put_pixel(ox as u32, oy as u32, *jpg.get_pixel(left + x, top + y));
+            }
+        }
+    }
+
+    let fout = &mut File::create(Path::new(out_file)).unwrap();
+    let _ = image::ImageRgb8(out).save(fout, image::PNG);
+    println!("successfully wrote to {}", out_file);
+}
+
+// Width and height big enough for all edges, used for distances in compare_edge_with_others
+fn edges_max_size(edges: &Vec<EdgeInfo>) -> (usize, usize) {
+    let mut max_x = 0;
+    let mut max_y = 0;
+    for edge in edges.iter() {
+        max_x = cmp::max(max_x, edge.max_x);
+        max_y = cmp::max(max_y, edge.max_y);
+    }
+    (max_x + 1, max_y + 1)
+}
+
+// Write num_best candidates of each edge with one-way and symmetric diffs to
+// candidates.json and candidates.csv. Candidates are computed without solved edges,
+// the solved partner is exported for comparison.
+fn export_candidates(data_dir: &str,
+                     solved_file: &str,
+                     num_best: usize,
+                     config: &HashMap<String, String>) {
+    let (mut edges, _, edge_nums) = read_edges(data_dir, config);
+    let (max_width, max_height) = edges_max_size(&edges);
+
+    let mut solved = HashMap::new();
+    for (i, j) in read_edge_pairs(solved_file, &edge_nums) {
+        solved.insert(i, j);
+        solved.insert(j, i);
+    }
+
+    let mut json = "{\n  \"edges\": [".to_string();
+    let mut csv = "edge,rank,candidate,same_piece,one_way,one_way_back,diff,rms,coverage,\
+                   coverage_back,confidence,solved\n"
+        .to_string();
+
+    for i in 0..edges.len() {
+        println!("candidates {}/{}", i + 1, edges.len());
+        compute_best_diff(i, &mut edges, num_best, max_width, max_height);
+
+        let i_name = edge_name(edges[i].edge_no);
+        let solved_name = match solved.get(&i) {
+            Some(j) => edge_name(edges[*j].edge_no),
+            None => "".to_string(),
+        };
+
+        if i > 0 {
+            json += ",";
+        }
+        let confidence = edge_confidence(&edges, i);
+        json += &format!("\n    {{\n      \"edge\": \"{}\",\n      \"confidence\": {:.4},\n      \
+                          \"solved\": {},\n      \"candidates\": [",
+                         i_name,
+                         confidence,
+                         if !solved_name.is_empty() {
+                             format!("\"{}\"", solved_name)
+                         } else {
+                             "null".to_string()
+                         });
+
+        for (rank, &(j, diff)) in edges[i].best_diff.iter().enumerate() {
+            let j_name = edge_name(edges[j].edge_no);
+            let same_piece = edges[i].edge_no >> 2 == edges[j].edge_no >> 2;
+            let one_way = compare_edges_one_way(&edges, i, j);
+            let one_way_back = compare_edges_one_way(&edges, j, i);
+            let similarity = edge_similarity(&edges, i, j);
+
+            if rank > 0 {
+                json += ",";
+            }
+            json += &format!("\n        {{\"rank\": {}, \"edge\": \"{}\", \"same_piece\": {}, \
+                              \"one_way\": {}, \"one_way_back\": {}, \"diff\": {}, \
+                              \"rms\": {:.4}, \"coverage\": {:.4}, \"coverage_back\": {:.4}}}",
+                             rank + 1,
+                             j_name,
+                             same_piece,
+                             one_way,
+                             one_way_back,
+                             diff,
+                             similarity.rms,
+                             similarity.coverage_a,
+                             similarity.coverage_b);
+            csv += &format!("{},{},{},{},{},{},{},{:.4},{:.4},{:.4},{:.4},{}\n",
+                            i_name,
+                            rank + 1,
+                            j_name,
+                            same_piece,
+                            one_way,
+                            one_way_back,
+                            diff,
+                            similarity.rms,
+                            similarity.coverage_a,
+                            similarity.coverage_b,
+                            confidence,
+                            solved_name);
+        }
+        json += "\n      ]\n    }";
+    }
+    json += "\n  ]\n}\n";
+
+    write_text_file("candidates.json", &json);
+    write_text_file("candidates.csv", &csv);
+}
+
+// Make file processed
+fn write_done_file(path: &str) {
+    let done_str = path.to_string() + ".done";
+    println!("writting done file {}", done_str);
+    let done_path = Path::new(&done_str);
+    match File::create(&done_path) {
+        Err(why) => {
+            panic!("couldn't create {}: {}",
+                   done_path.display(),
+                   why.description())
+        }
+        Ok(file) => file,
+    };
+}
+
+// Is file already processed?
+fn is_done(path: &str) -> bool {
+    let done_str = path.to_string() + ".done";
+    let done_path = Path::new(&done_str);
+    if !done_path.exists() {
+        return false;
+    }
+    println!("skipping {} because {} exists", path, done_path.display());
+
+    return true;
+}
+
+const HELP: &str = "usage: puzzle [command] [options] [args]
+
+commands:
+  (none) [piece...]          cut, extract and solve, prefer given pieces
+  cut                        crop pieces from photos in jpg dir to data dir
+  extract [--auto-tune]      find edges of pieces in data dir
+  tune [piece...]            find extraction parameters, they are saved to puzzle.cfg
+  review [--rerun]           check extracted pieces, write suspects to review.txt in current
+                             dir and with --rerun extract them again
+  score [a.s b.t]            diff of two edges or the best candidate of each edge
+  solve [piece...]           interactive solver
+  auto-solve [piece...]      solver accepting clear matches without asking
+  check                      check that 2x2 blocks of solved edges close
+  clusters                   print clusters of solved pieces
+  export svg [--layout] [piece...]
+  export candidates [num_best]
+  export png [out.png]       render assembled puzzle from photos
+
+options:
+  --data DIR                 pieces and edges (./data)
+  --jpg DIR                  photos (./jpg)
+  --solved FILE              solved edges (solved_edges.txt), solved_history.txt and
+                             rejected_edges.txt are next to it
+  --window WxH               window size for cut and extract (2000x2000)
+  --frontier                 propose quads on border of the biggest cluster
+  --auto-max-score N         auto-solve score threshold
+  --auto-min-margin F        auto-solve second best / best ratio
+  --candidates-b/c/d N       candidates for each leg of quad
+  --top-quads N              number of quads in summary table
+  --KEY VALUE                any other puzzle.cfg key, - is replaced by _
+  -h, --help                 this help";
+
+// Split command line to command, its arguments and boolean flags. Other --key value
+// options are put to config so that they override puzzle.cfg.
+fn parse_args(args: &Vec<String>,
+              config: &mut HashMap<String, String>)
+              -> (String, Vec<String>, Vec<String>) {
+    let bool_flags = ["help", "frontier", "layout", "auto-tune", "rerun"];
+    let mut command = String::new();
+    let mut params = vec![];
+    let mut flags = vec![];
+
+    let mut i = 0;
+    while i < args.len() {
+        let arg = &args[i];
+        i += 1;
+        if arg == "-h" {
+            flags.push("help".to_string());
+            continue;
+        }
+        if !arg.starts_with("--") {
+            // The first word is command unless it's piece number
+            if command.is_empty() && params.is_empty() && arg.parse::<usize>().is_err() {
+                command = arg.clone();
+            } else {
+                params.push(arg.clone());
+            }
+            continue;
+        }
+
+        let (name, value) = match arg[2..].find('=') {
+            Some(pos) => (arg[2..2 + pos].to_string(), Some(arg[3 + pos..].to_string())),
+            None => (arg[2..].to_string(), None),
+        };
+        if bool_flags.contains(&name.as_str()) {
+            flags.push(name);
+            continue;
+        }
+        let value = match value {
+            Some(value) => value,
+            None if i < args.len() => {
+                i += 1;
+                args[i - 1].clone()
+            }
+            None => panic!("missing value for --{}", name),
+        };
+        match name.as_str() {
+            "data" => config.insert("data_dir".to_string(), value),
+            "jpg" => config.insert("jpg_dir".to_string(), value),
+            "solved" => config.insert("solved_file".to_string(), value),
+            "window" => {
+                let v: Vec<&str> = value.split('x').collect();
+                if v.len() != 2 {
+                    panic!("--window must be WIDTHxHEIGHT, got {}", value);
+                }
+                config.insert("window_width".to_string(), v[0].to_string());
+                config.insert("window_height".to_string(), v[1].to_string())
+            }
+            _ => {
+                // Typo would take the next argument as its value
+                let key = name.replace("-", "_");
+                if !CONFIG_KEYS.contains(&key.as_str()) &&
+                   !CONFIG_KEY_PREFIXES.iter().any(|p| key.starts_with(p)) {
+                    println!("WARNING unknown option --{}, {} is taken as its value", name, value);
+                }
+                config.insert(key, value)
+            }
+        };
+    }
+    (command, params, flags)
+}
+
+// Window size for cut and extract
+fn window_size(config: &HashMap<String, String>) -> (usize, usize) {
+    (config_f64(config, "window_width").unwrap_or(WND_WIDTH as f64) as usize,
+     config_f64(config, "window_height").unwrap_or(WND_HEIGHT as f64) as usize)
+}
+
+// Window for pixels of given size: not bigger then window size from config and 90% of
+// the monitor
+fn viewer_size(video_subsystem: &sdl2::VideoSubsystem,
+               config: &HashMap<String, String>,
+               width: usize,
+               height: usize)
+               -> (usize, usize) {
+    let (max_width, max_height) = window_size(config);
+    let mut res = (cmp::min(width, max_width), cmp::min(height, max_height));
+    if let Ok(mode) = video_subsystem.desktop_display_mode(0) {
+        res.0 = cmp::min(res.0, mode.w as usize * 9 / 10);
+        res.1 = cmp::min(res.1, mode.h as usize * 9 / 10);
+    }
+    res
+}
+
+// Crop pieces from all not yet done .jpg files
+fn cut_jpgs(jpg_dir: &str,
+            data_dir: &str,
+            sdl_context: &sdl2::Sdl,
+            config: &HashMap<String, String>) {
+    fs::create_dir_all(data_dir).unwrap();
+    let entries = fs::read_dir(jpg_dir).unwrap();
+    for entry in entries {
+        //println!("Name: {}", path.unwrap().path().into_os_string().into_string());
 
         let path = entry.unwrap().path();
         match path.extension().and_then(OsStr::to_str) {
@@ -1517,14 +4550,22 @@ fn main() {
                 if is_done(&path_str) {
                     continue;
                 }
-                process_jpg(&path_str, jpg_no, &sdl_context);
+                process_jpg(&path_str, jpg_no, data_dir, sdl_context, config);
             }
             _ => {}
         }
     }
+}
 
-    // Process all .png files - this will write 4 txt files for each edge
-    let entries = fs::read_dir("./data").unwrap();
+// Process all not yet done .png files - this will write 4 txt files for each edge. With
+// auto_tune extraction parameters are tuned for each png first.
+fn extract_pngs(data_dir: &str,
+                sdl_context: &sdl2::Sdl,
+                display_state: &mut DisplayPixelState,
+                font: Option<&Font>,
+                auto_tune: bool,
+                config: &HashMap<String, String>) {
+    let entries = fs::read_dir(data_dir).unwrap();
     for entry in entries {
         //println!("Name: {}", path.unwrap().path().into_os_string().into_string());
 
@@ -1541,70 +4582,248 @@ fn main() {
                 if is_done(&path_str) {
                     continue;
                 }
-                process_png(&path_str, png_no, &sdl_context, &mut display_state);
+                let params = if auto_tune {
+                    tune_extraction(&path_str, png_no, config)
+                } else {
+                    extraction_params(config, png_no)
+                };
+                process_png(&path_str,
+                            png_no,
+                            sdl_context,
+                            display_state,
+                            font,
+                            &params,
+                            config);
             }
             _ => {}
         }
     }
-    //process_png("9.png", &sdl_context);
+}
+
+// Print diff and similarity of two edges, or the best candidate of each edge
+fn print_scores(data_dir: &str,
+                solved_file: &str,
+                pair: Option<(&str, &str)>,
+                config: &HashMap<String, String>) {
+    let (mut edges, _, edge_nums) = read_edges(data_dir, config);
+    let (max_width, max_height) = edges_max_size(&edges);
+
+    let edge_index = |name: &str| -> usize {
+        let num: usize = name.replace(".", "").parse().unwrap();
+        match edge_nums.get(&(4 * (num / 10) + (num % 10))) {
+            Some(i) => *i,
+            None => panic!("edge {} not found", name),
+        }
+    };
+
+    if let Some((a, b)) = pair {
+        let (i, j) = (edge_index(a), edge_index(b));
+        let similarity = edge_similarity(&edges, i, j);
+        println!("{}->{} diff {}+{}={} rms {:.2} coverage {:.0}%/{:.0}%",
+                 a,
+                 b,
+                 compare_edges_one_way(&edges, i, j),
+                 compare_edges_one_way(&edges, j, i),
+                 compare_edges(&edges, i, j),
+                 similarity.rms,
+                 100.0 * similarity.coverage_a,
+                 100.0 * similarity.coverage_b);
+        return;
+    }
 
-    // Read txt files with edges
-    let mut edges = vec![];
-    let mut pieces = HashMap::new();
-    let entries = fs::read_dir("./data").unwrap();
-    for entry in entries {
+    let mut solved = HashMap::new();
+    for (i, j) in read_edge_pairs(solved_file, &edge_nums) {
+        solved.insert(i, j);
+        solved.insert(j, i);
+    }
 
-        let path = entry.unwrap().path();
-        if path.extension().unwrap() != "txt" {
-            continue;
-        }
+    println!("  edge   best         diff    rms confidence solved");
+    for i in 0..edges.len() {
+        // The best from other piece
+        compute_best_diff(i, &mut edges, 4, max_width, max_height);
+        let i_piece = edges[i].edge_no >> 2;
+        let best = edges[i].best_diff.iter().find(|b| edges[b.0].edge_no >> 2 != i_piece);
+        let (j, diff) = match best {
+            Some(b) => *b,
+            None => continue,
+        };
+        println!("{:>6} {:>6} {:>12} {:>6.2} {:>10.2} {}",
+                 edge_name(edges[i].edge_no),
+                 edge_name(edges[j].edge_no),
+                 diff,
+                 edge_similarity(&edges, i, j).rms,
+                 edge_confidence(&edges, i),
+                 match solved.get(&i) {
+                     Some(k) => edge_name(edges[*k].edge_no),
+                     None => "".to_string(),
+                 });
+    }
+}
 
-        let (edge_no, piece_no) = {
+fn main() {
+    let mut config = read_config(CONFIG_FILE);
 
-            let file_stem = path.file_stem()
-                .unwrap()
-                .to_str()
-                .unwrap();
+    let args: Vec<String> = env::args().skip(1).collect();
+    let (command, params, flags) = parse_args(&args, &mut config);
+    if flags.iter().any(|f| f == "help") {
+        println!("{}", HELP);
+        return;
+    }
 
-            let filename_nums: usize = file_stem.replace(".", "").parse().unwrap();
+    let data_dir = config_str(&config, "data_dir", "./data");
+    let jpg_dir = config_str(&config, "jpg_dir", "./jpg");
+    let solved_file = config_str(&config, "solved_file", "solved_edges.txt");
 
-            if file_stem.contains(".") {
-                // edge no: 12.3.txt -> 123 -> 4 * 12 + 3
-                (4 * (filename_nums / 10) + (filename_nums % 10), usize::max_value())
+    // Commands without SDL
+    match command.as_str() {
+        "clusters" => {
+            print_clusters(&data_dir, &solved_file, &config);
+            return;
+        }
+        "check" => {
+            print_check(&data_dir, &solved_file, &config);
+            return;
+        }
+        "review" => {
+            review_extractions(&data_dir, flags.iter().any(|f| f == "rerun"), &config);
+            return;
+        }
+        "tune" => {
+            // puzzle tune [piece_no...]
+            for entry in fs::read_dir(&data_dir).unwrap() {
+                let path = entry.unwrap().path();
+                if path.extension().and_then(OsStr::to_str) != Some("png") {
+                    continue;
+                }
+                let png_no: usize = path.file_stem().unwrap().to_str().unwrap().parse().unwrap();
+                if !params.is_empty() && !params.iter().any(|p| *p == png_no.to_string()) {
+                    continue;
+                }
+                tune_extraction(&path.into_os_string().into_string().unwrap(), png_no, &config);
+            }
+            return;
+        }
+        "score" => {
+            // puzzle score [edge edge]
+            let pair = match params.len() {
+                0 => None,
+                2 => Some((params[0].as_str(), params[1].as_str())),
+                _ => {
+                    println!("score needs two edges, e.g. puzzle score 12.3 45.0\n\n{}", HELP);
+                    return;
+                }
+            };
+            print_scores(&data_dir, &solved_file, pair, &config);
+            return;
+        }
+        "export" => {
+            let (format, params) = if params.is_empty() {
+                ("", &params[..])
             } else {
-                (usize::max_value(), filename_nums) // piece_no
+                (params[0].as_str(), &params[1..])
+            };
+            match format {
+                "png" => {
+                    // puzzle export png [out.png]
+                    let out_file = if !params.is_empty() {
+                        params[0].clone()
+                    } else {
+                        "assembled.png".to_string()
+                    };
+                    render_assembled(&data_dir, &solved_file, &out_file, &config);
+                }
+                "candidates" => {
+                    // puzzle export candidates [num_best]
+                    let num_best = params.first().map_or(Some(10), |p| p.parse().ok());
+                    match num_best {
+                        Some(num_best) if num_best > 0 => {
+                            export_candidates(&data_dir, &solved_file, num_best, &config)
+                        }
+                        _ => println!("num_best must be number > 0\n\n{}", HELP),
+                    }
+                }
+                "svg" => {
+                    // puzzle export svg [--layout] [piece_no...]
+                    let layout = flags.iter().any(|f| f == "layout");
+                    let piece_nums = params.iter().filter_map(|a| a.parse().ok()).collect();
+                    export_svg(&data_dir, &solved_file, &piece_nums, layout, &config);
+                }
+                _ => println!("export format must be svg, candidates or png\n\n{}", HELP),
             }
-        };
+            return;
+        }
+        "" | "cut" | "extract" | "solve" | "auto-solve" => {}
+        _ => {
+            println!("unknown command {}\n\n{}", command, HELP);
+            return;
+        }
+    }
 
-        let path_str = path.into_os_string().into_string().unwrap();
-        let points = read_txt(&path_str);
+    let sdl_context = sdl2::init().unwrap();
 
-        // If it's pieces, just read points
-        if piece_no != usize::max_value() {
-            pieces.insert(piece_no, points);
-            continue;
-        }
+    let mut display_state = DisplayPixelState { autorotate: false };
 
-        // It's edge. Compute height and add EdgeInfo
-        let mut max_x = 0;
-        let mut max_y = 0;
-        for p in points.iter() {
-            max_x = cmp::max(max_x, p.0);
-            max_y = cmp::max(max_y, p.1);
+    // Font for HUD, without it texts go just to stdout
+    let ttf_context = sdl2::ttf::init().unwrap();
+    let font_path = config_str(&config, "font", DEFAULT_FONT);
+    let font = match ttf_context.load_font(Path::new(&font_path), 16) {
+        Ok(font) => Some(font),
+        Err(e) => {
+            println!("couldn't load font {}: {}", font_path, e);
+            None
         }
+    };
 
-        let edge_info = EdgeInfo {
-            points: points,
-            edge_no: edge_no,
-            max_x: max_x,
-            max_y: max_y,
-            diff_to: vec![],
-            best_diff: vec![],
-            edge_index: usize::max_value(),
-            solved_index: usize::max_value(),
-        };
-        edges.push(edge_info);
+    // Without command do everything
+    if command.is_empty() || command == "cut" {
+        cut_jpgs(&jpg_dir, &data_dir, &sdl_context, &config);
+    }
+    if command.is_empty() || command == "extract" {
+        extract_pngs(&data_dir,
+                     &sdl_context,
+                     &mut display_state,
+                     font.as_ref(),
+                     flags.iter().any(|f| f == "auto-tune"),
+                     &config);
     }
+    if command == "cut" || command == "extract" {
+        return;
+    }
+
+    let options = SolveOptions {
+        pref_pieces: params.iter()
+            .map(|p| match p.parse() {
+                Ok(piece_no) => piece_no,
+                Err(_) => panic!("piece number expected, got {}\n\n{}", p, HELP),
+            })
+            .collect(),
+        auto_solve: command == "auto-solve",
+        frontier_mode: flags.iter().any(|f| f == "frontier"),
+    };
+    solve(&data_dir,
+          &solved_file,
+          &options,
+          &sdl_context,
+          &mut display_state,
+          font.as_ref(),
+          &config);
+}
+
+// Interactive solver. With frontier_mode the next quad is always proposed on the border
+// of the biggest cluster, where the most of neighbour pieces are already placed.
+fn solve(data_dir: &str,
+         solved_file: &str,
+         options: &SolveOptions,
+         sdl_context: &sdl2::Sdl,
+         display_state: &mut DisplayPixelState,
+         font: Option<&Font>,
+         config: &HashMap<String, String>) {
+    let pref_pieces = &options.pref_pieces;
+    let auto_solve = options.auto_solve;
+    let frontier_mode = options.frontier_mode;
+
+    // Read txt files with edges
+    let (mut edges, mut pieces, edge_nums) = read_edges(data_dir, config);
 
     let edges_len = edges.len();
 
@@ -1640,37 +4859,32 @@ fn main() {
     let mut pixels: Vec<u8> = vec![0;3*sqr*sqr];
     let video_subsystem = sdl_context.video().unwrap();
 
-    let window = video_subsystem.window("puzzle solver", sqr as u32, sqr as u32)
+    let (wnd_width, wnd_height) = viewer_size(&video_subsystem, config, sqr, sqr);
+    let window = video_subsystem.window("puzzle solver", wnd_width as u32, wnd_height as u32)
         .opengl()
         .build()
         .unwrap();
 
     let mut renderer = window.renderer().build().unwrap();
 
-    // Hashmap to get index by edge_no
-    let mut edge_nums = HashMap::new();
-    for i in 0..edges_len {
-        let ref mut edge_i = edges[i];
-        let i_no = edge_i.edge_no;
-        //println!("edge={}.{}", i_no >> 2, i_no & 3);
-        edge_nums.insert(i_no, i);
-        edge_i.edge_index = i;
-    }
-
     // Solved edges
     let mut pref_solved = vec![];
-    for p in read_txt("solved_edges.txt") {
-        let i_no = 4 * (p.0 / 10) + (p.0 % 10); // edge no: 12.3 -> 123 -> 4 * 12 + 3
-        let j_no = 4 * (p.1 / 10) + (p.1 % 10);
-        print!("solved edge {:>4}.{}->{:>4}.{}",
+    for (i_index, j_index, fields) in read_edge_lines(solved_file, &edge_nums) {
+        let i_no = edges[i_index].edge_no;
+        let j_no = edges[j_index].edge_no;
+
+        // Old two column lines have no source
+        let source = if !fields.is_empty() { fields[0].clone() } else { "imported".to_string() };
+        print!("solved edge {:>4}.{}->{:>4}.{} {:<8}",
                i_no >> 2,
                i_no & 3,
                j_no >> 2,
-               j_no & 3);
-        let i_index = *edge_nums.get(&i_no).unwrap();
-        let j_index = *edge_nums.get(&j_no).unwrap();
+               j_no & 3,
+               source);
         edges[i_index].solved_index = j_index;
         edges[j_index].solved_index = i_index;
+        edges[i_index].solved_source = source.clone();
+        edges[j_index].solved_source = source;
 
         let diff_ij = compare_edges(&edges, i_index, j_index);
         let diff_ji = compare_edges(&edges, j_index, i_index);
@@ -1684,14 +4898,39 @@ fn main() {
                  diff_ij + diff_ji);
     }
 
+    let (_, violations) = check_cycles(&edges, &edge_nums);
+    if !violations.is_empty() {
+        println!("WARNING {} 2x2 blocks of solved edges do not close, see puzzle check",
+                 violations.len());
+    }
+
+    // Pairs user marked as not fitting
+    let history_file = solved_file_sibling(solved_file, "solved_history.txt");
+    let rejected_file = solved_file_sibling(solved_file, "rejected_edges.txt");
+    if Path::new(&rejected_file).exists() {
+        for (i_index, j_index) in read_edge_pairs(&rejected_file, &edge_nums) {
+            edges[i_index].rejected.push(j_index);
+            edges[j_index].rejected.push(i_index);
+        }
+    }
+
+    // Auto-solve accepts quads with low score that are clearly better then the second
+    // best combination, the user decides only ambiguous ones
+    let auto_max_score = config_f64(config, "auto_max_score").unwrap_or(50000.0) as usize;
+    let auto_min_margin = config_f64(config, "auto_min_margin").unwrap_or(2.0);
+    if auto_solve {
+        println!("auto-solve: accepting score <= {} and second best >= {} * score",
+                 auto_max_score,
+                 auto_min_margin);
+    }
+
     // Prefer pieces from command line
     let mut pref_cmd_solved = vec![];
     let mut pref_cmd_unsolved = vec![];
-    for arg in env::args().skip(1) {
-        let argv: usize = arg.parse().unwrap();
+    for piece_no in pref_pieces.iter() {
         for i in 0..edges_len {
             let png_no = edges[i].edge_no >> 2;
-            if png_no != argv {
+            if png_no != *piece_no {
                 continue;
             }
             if edges[i].solved_index == usize::max_value() {
@@ -1717,9 +4956,57 @@ fn main() {
 
     let mut pref_new = vec![];
 
-    let mut combi_shift = 0;
+    // Written to solved_edges.txt to tell which pairs were accepted together
+    let session = unix_time();
+
+    // Similarity of quad legs shown in HUD, (edge_index, edge_index) -> similarity
+    let mut similarities: HashMap<(usize, usize), EdgeSimilarity> = HashMap::new();
+
+    // Candidates tried for each leg of the quad, both ways around the loop with
+    // search_reverse. The best num_top_quads are listed, worse partial scores are pruned.
+    let leg_candidates = |key: &str| {
+        let num = config_f64(config, key).unwrap_or(1.0);
+        if num.is_nan() || num < 1.0 {
+            println!("{} must be number >= 1, got {}, using 1", key, num);
+            return 1;
+        }
+        num as usize
+    };
+    let candidates_b = leg_candidates("candidates_b");
+    let candidates_c = leg_candidates("candidates_c");
+    let candidates_d = leg_candidates("candidates_d");
+    let search_reverse = config_f64(config, "search_reverse").unwrap_or(0.0) != 0.0;
+    let num_top_quads = cmp::max(config_f64(config, "top_quads").unwrap_or(5.0) as usize, 2);
+
+    // Block matcher (B key): candidates for each edge and number of kept partial blocks
+    let block_candidates = config_f64(config, "block_candidates").unwrap_or(3.0) as usize;
+    let block_beam = config_f64(config, "block_beam").unwrap_or(20.0) as usize;
+
+    // Auto-solve needs at least two combinations to know the margin
+    let mut combi_shift = if auto_solve { 1 } else { 0 };
+
+    // Solve decisions made in this session
+    let mut undo_stack: Vec<SolvedStep> = vec![];
+    let mut redo_stack: Vec<SolvedStep> = vec![];
+
+    // Edges clicked by mouse, first edge and then (edge, edge, diff) waiting for confirm
+    let mut manual_first: Option<usize> = None;
+    let mut manual_pair: Option<(usize, usize, usize)> = None;
 
     loop {
+        // Frontier changes with each solved quad, user choice goes first once
+        if frontier_mode {
+            pref_indices = frontier_edges(&edges, &edge_nums);
+            if pref_indices.is_empty() {
+                println!("no frontier, trying all edges");
+                pref_indices = (0..edges_len).collect();
+            }
+            for pref in pref_new.iter() {
+                pref_indices.insert(0, *pref);
+            }
+            pref_new.clear();
+        }
+
         for pref in pref_new.iter() {
             pref_indices.insert(0, *pref);
         }
@@ -1734,14 +5021,16 @@ fn main() {
             let a_no = edges[a].edge_no;
 
             // Loop to compare combination of best edges, e.g. 1stJ..1stP, 1stJ..2ndM, 2ndJ..2ndM
-            let mut best_final_score = usize::max_value();
+            let mut top_quads: Vec<RankedQuad> = vec![];
+            let mut second_final_score = usize::max_value();
             let mut combi_counter = 0;
-            let mut best_combi_counter = 0;
 
-            // Parameter for edge matching combinations
-            let combi_one_edge = 1 << combi_shift; // number of combinations for one edge
-            let combi_mask = combi_one_edge - 1;
-            let combi_all = combi_one_edge * combi_one_edge * combi_one_edge;
+            // Number of candidates for each leg, C key doubles them
+            let counts = (candidates_b << combi_shift,
+                          candidates_c << combi_shift,
+                          candidates_d << combi_shift);
+            let combi_one_way = counts.0 * counts.1 * counts.2;
+            let combi_all = if search_reverse { 2 * combi_one_way } else { combi_one_way };
 
             'combi_loop: loop {
 
@@ -1749,6 +5038,19 @@ fn main() {
                 let combi_val = if combi_counter <= combi_all - 1 {
                     combi_counter
                 } else if combi_counter == combi_all {
+                    if top_quads.is_empty() {
+                        println!("no usable quad for {}", edge_name(a_no));
+                        break 'combi_loop;
+                    }
+                    let best_combi_counter = top_quads[0].1;
+                    if top_quads.len() > 1 {
+                        second_final_score = top_quads[1].0;
+                    }
+                    println!("======= TOP {} QUADS ========", top_quads.len());
+                    println!("rank combi        score  pairs");
+                    for (k, q) in top_quads.iter().enumerate() {
+                        println!("{:>4} {:>5} {:>12}  {}", k + 1, q.1, q.0, q.2);
+                    }
                     println!("======= BEST MATCH {:>2} ========", best_combi_counter);
                     display_state.autorotate = false;
                     best_combi_counter
@@ -1756,135 +5058,156 @@ fn main() {
                     break 'combi_loop;
                 };
 
-                let combi = (combi_val & combi_mask,
-                             (combi_val >> combi_shift) & combi_mask,
-                             (combi_val >> (2 * combi_shift)) & combi_mask);
+                // Candidate for each leg and the direction of the loop
+                let reverse = combi_val >= combi_one_way;
+                let leg_val = combi_val % combi_one_way;
+                let combi = (leg_val % counts.0,
+                             (leg_val / counts.0) % counts.1,
+                             leg_val / (counts.0 * counts.1));
+                let best_round = combi_counter == combi_all;
                 if combi_counter != combi_all {
-                    println!("------------------------------              combi {}=>{}.{}.{}",
+                    println!("------------------------------              combi {}=>{}.{}.{}{}",
                              combi_counter,
                              combi.0,
                              combi.1,
-                             combi.2);
+                             combi.2,
+                             if reverse { " reverse" } else { "" });
                 }
 
                 combi_counter += 1;
 
-                //     B  <-  A
-                let (b, b_no, diff_b) = get_best_diff(a,
-                                                      &mut edges,
-                                                      combi.0,
-                                                      combi_one_edge,
-                                                      max_width,
-                                                      max_height);
-
-                println!("{:>4}.{}->{:>4}.{}                 {:>12}",
-                         a_no >> 2,
-                         a_no & 3,
-                         b_no >> 2,
-                         b_no & 3,
-                         diff_b);
-
-                //     C
-                //     ^
-                //     |
-                //     B  <-  A
-                let b_plus_no = side_plus(b_no);
-                let b_plus = *edge_nums.get(&b_plus_no).unwrap();
-                let (c, c_no, diff_c) = get_best_diff(b_plus,
-                                                      &mut edges,
-                                                      combi.1,
-                                                      combi_one_edge,
-                                                      max_width,
-                                                      max_height);
-
-                println!("        {:>4}.{}->{:>4}.{}         {:>12}",
-                         b_plus_no >> 2,
-                         b_plus_no & 3,
-                         c_no >> 2,
-                         c_no & 3,
-                         diff_c);
-
-                //     C  ->  D
-                //     ^
-                //     |
-                //     B  <-  A
-                let c_plus_no = side_plus(c_no);
-                let c_plus = *edge_nums.get(&c_plus_no).unwrap();
-                let (d, d_no, diff_d) = get_best_diff(c_plus,
-                                                      &mut edges,
-                                                      combi.2,
-                                                      combi_one_edge,
-                                                      max_width,
-                                                      max_height);
-
-                println!("                {:>4}.{}->{:>4}.{} {:>12}",
-                         c_plus_no >> 2,
-                         c_plus_no & 3,
-                         d_no >> 2,
-                         d_no & 3,
-                         diff_d);
-
-                // Now check A->D - must be small if pieces fit
-                //
-                //     C  ->  D
-                //     ^      ^
-                //     |      |
-                //     B  <-  A
-                //
-                // The last edge can be marked as solved and thus not loaded
-                let d_plus_no = side_plus(d_no);
-                let d_plus = *edge_nums.get(&d_plus_no).unwrap();
+                // Combinations with partial score worse then the last of top quads are pruned
+                let prune_score = if !best_round && top_quads.len() >= num_top_quads {
+                    top_quads[num_top_quads - 1].0
+                } else {
+                    usize::max_value()
+                };
 
                 let a_minus_no = side_minus(a_no);
                 let a_minus = *edge_nums.get(&a_minus_no).unwrap();
 
-                let mut diff_a_minus = compare_edges(&mut edges, a_minus, d_plus) +
-                                       compare_edges(&mut edges, d_plus, a_minus);
+                let (b, b_no, diff_b, b_plus, b_plus_no, c, c_no, diff_c, c_plus, c_plus_no, d,
+                     d_no, diff_d, d_plus, d_plus_no, diff_a_minus) = if !reverse {
+
+                    //     B  <-  A
+                    let (b, b_no, diff_b) = get_best_diff(a,
+                                                          &mut edges,
+                                                          combi.0,
+                                                          counts.0,
+                                                          max_width,
+                                                          max_height);
+                    print_leg(0, a_no, b_no, diff_b);
+                    if diff_b >= prune_score {
+                        continue 'combi_loop;
+                    }
 
+                    //     C
+                    //     ^
+                    //     |
+                    //     B  <-  A
+                    let b_plus_no = side_plus(b_no);
+                    let b_plus = *edge_nums.get(&b_plus_no).unwrap();
+                    let (c, c_no, diff_c) = get_best_diff(b_plus,
+                                                          &mut edges,
+                                                          combi.1,
+                                                          counts.1,
+                                                          max_width,
+                                                          max_height);
+                    print_leg(1, b_plus_no, c_no, diff_c);
+                    if diff_b + diff_c >= prune_score {
+                        continue 'combi_loop;
+                    }
 
-                // Check if it's not the same edge
-                if d_plus == a_minus {
-                    println!("SKIP d_plus and a_minus is same edge");
-                    diff_a_minus += 100000000;
-                }
+                    //     C  ->  D
+                    //     ^
+                    //     |
+                    //     B  <-  A
+                    let c_plus_no = side_plus(c_no);
+                    let c_plus = *edge_nums.get(&c_plus_no).unwrap();
+                    let (d, d_no, diff_d) = get_best_diff(c_plus,
+                                                          &mut edges,
+                                                          combi.2,
+                                                          counts.2,
+                                                          max_width,
+                                                          max_height);
+                    print_leg(2, c_plus_no, d_no, diff_d);
+                    if diff_b + diff_c + diff_d >= prune_score {
+                        continue 'combi_loop;
+                    }
 
-                // Check if solved d->a match
-                let d_plus_solved_index = edges[d_plus].solved_index;
-                if d_plus_solved_index != usize::max_value() {
-                    diff_a_minus = if d_plus_solved_index == a_minus {
-                        0
-                    } else {
-                        println!("SKIP {}.{} is already solved to {}.{} and does not match {}.{}",
-                                 d_plus_no >> 2,
-                                 d_plus_no & 3,
-                                 edges[d_plus_solved_index].edge_no >> 2,
-                                 edges[d_plus_solved_index].edge_no & 3,
-                                 a_minus_no >> 2,
-                                 a_minus_no & 3);
-                        diff_a_minus + 100000000
+                    // Now check A->D - must be small if pieces fit
+                    //
+                    //     C  ->  D
+                    //     ^      ^
+                    //     |      |
+                    //     B  <-  A
+                    //
+                    // The last edge can be marked as solved and thus not loaded
+                    let d_plus_no = side_plus(d_no);
+                    let d_plus = *edge_nums.get(&d_plus_no).unwrap();
+                    let diff_a_minus = closing_diff(&edges, d_plus, a_minus, 2);
+
+                    (b, b_no, diff_b, b_plus, b_plus_no, c, c_no, diff_c, c_plus, c_plus_no, d,
+                     d_no, diff_d, d_plus, d_plus_no, diff_a_minus)
+                } else {
+
+                    // The same loop the other way, A's partner B is given by the loop
+                    //     C  <-  D
+                    //            ^
+                    //            |
+                    //            A
+                    let (d_plus, d_plus_no, diff_a_minus) = get_best_diff(a_minus,
+                                                                          &mut edges,
+                                                                          combi.0,
+                                                                          counts.0,
+                                                                          max_width,
+                                                                          max_height);
+                    print_leg(0, a_minus_no, d_plus_no, diff_a_minus);
+                    if diff_a_minus >= prune_score {
+                        continue 'combi_loop;
                     }
-                }
-                let a_minus_solved_index = edges[a_minus].solved_index;
-                if a_minus_solved_index != usize::max_value() {
-                    diff_a_minus = if a_minus_solved_index == d_plus {
-                        if diff_a_minus == 0 {
-                            0
-                        } else {
-                            panic!("a_minus solved to d_plus but d_plus solved to other");
-                        }
-                    } else {
-                        println!("SKIP {}.{} is already solved to {}.{} and does not match {}.{}",
-                                 a_minus_no >> 2,
-                                 a_minus_no & 3,
-                                 edges[a_minus_solved_index].edge_no >> 2,
-                                 edges[a_minus_solved_index].edge_no & 3,
-                                 d_plus_no >> 2,
-                                 d_plus_no & 3);
-                        diff_a_minus + 100000000
+
+                    let d_no = side_minus(d_plus_no);
+                    let d = *edge_nums.get(&d_no).unwrap();
+                    let (c_plus, c_plus_no, diff_d) = get_best_diff(d,
+                                                                    &mut edges,
+                                                                    combi.1,
+                                                                    counts.1,
+                                                                    max_width,
+                                                                    max_height);
+                    print_leg(1, d_no, c_plus_no, diff_d);
+                    if diff_a_minus + diff_d >= prune_score {
+                        continue 'combi_loop;
                     }
-                }
 
-                let mut skip_draw = display_state.autorotate;
+                    //     C  <-  D
+                    //     |      ^
+                    //     v      |
+                    //     B      A
+                    let c_no = side_minus(c_plus_no);
+                    let c = *edge_nums.get(&c_no).unwrap();
+                    let (b_plus, b_plus_no, diff_c) = get_best_diff(c,
+                                                                    &mut edges,
+                                                                    combi.2,
+                                                                    counts.2,
+                                                                    max_width,
+                                                                    max_height);
+                    print_leg(2, c_no, b_plus_no, diff_c);
+                    if diff_a_minus + diff_d + diff_c >= prune_score {
+                        continue 'combi_loop;
+                    }
+
+                    // B->A closes the loop
+                    let b_no = side_minus(b_plus_no);
+                    let b = *edge_nums.get(&b_no).unwrap();
+                    let diff_b = closing_diff(&edges, b, a, 2);
+
+                    (b, b_no, diff_b, b_plus, b_plus_no, c, c_no, diff_c, c_plus, c_plus_no, d,
+                     d_no, diff_d, d_plus, d_plus_no, diff_a_minus)
+                };
+
+                // Auto-solve draws only the best match
+                let mut skip_draw = display_state.autorotate || (auto_solve && !best_round);
 
                 let final_score = diff_b + diff_c + diff_d + diff_a_minus;
 
@@ -1896,11 +5219,41 @@ fn main() {
                          diff_a_minus,
                          final_score);
 
-                // Remeber best 4-edge diff that will be displayed after all cominations computed
-                if final_score < best_final_score {
-                    best_final_score = final_score;
-                    best_combi_counter = combi_val;
-                    skip_draw = false; // always draw the best matching
+                // Remeber best 4-edge diffs, the best will be displayed after all cominations
+                if !best_round {
+                    // Quad found both ways around the loop is listed once with better score
+                    let mut quad_key: Vec<(usize, usize)> =
+                        [(a, b), (b_plus, c), (c_plus, d), (d_plus, a_minus)]
+                            .iter()
+                            .map(|p| (cmp::min(p.0, p.1), cmp::max(p.0, p.1)))
+                            .collect();
+                    quad_key.sort();
+                    let mut pos = top_quads.iter()
+                        .position(|q| q.0 > final_score)
+                        .unwrap_or(top_quads.len());
+                    match top_quads.iter().position(|q| q.3 == quad_key) {
+                        Some(same) if same < pos => pos = num_top_quads, // already better
+                        Some(same) => {
+                            top_quads.remove(same);
+                        }
+                        None => {}
+                    }
+                    if pos < num_top_quads {
+                        let pairs = format!("{}->{} {}->{} {}->{} {}->{}",
+                                            edge_name(a_no),
+                                            edge_name(b_no),
+                                            edge_name(b_plus_no),
+                                            edge_name(c_no),
+                                            edge_name(c_plus_no),
+                                            edge_name(d_no),
+                                            edge_name(d_plus_no),
+                                            edge_name(a_minus_no));
+                        top_quads.insert(pos, (final_score, combi_val, pairs, quad_key));
+                        top_quads.truncate(num_top_quads);
+                    }
+                    if pos == 0 {
+                        skip_draw = auto_solve; // always draw the best matching
+                    }
                 }
 
                 if skip_draw {
@@ -1968,38 +5321,148 @@ fn main() {
                             col_d.1,
                             col_d.2);
 
+                // Edge and piece labels with diffs, score and keys help
+                let mut hud = Hud {
+                    labels: vec![],
+                    lines: vec![format!("FINAL SCORE={}  combi {}/{}",
+                                        final_score,
+                                        combi_val,
+                                        combi_all),
+                                "S solve  U undo  shift-U redo  X reject pair (1-4 first)  C more \
+                                 combinations  D re-extract A,B  A autorotate  B block (2 for 2x3)"
+                                    .to_string(),
+                                "click two edges + Y/N pair  0-9 Enter piece  arrows/P/M move \
+                                 and zoom  Esc quit"
+                                    .to_string(),
+                                "solved pieces: white manual, cyan auto, grey imported"
+                                    .to_string()],
+                    textures: None,
+                };
+                let legs = [(a_no, b_no, diff_b),
+                            (b_plus_no, c_no, diff_c),
+                            (c_plus_no, d_no, diff_d),
+                            (d_plus_no, a_minus_no, diff_a_minus)];
+                let leg_indices = [(a, b), (b_plus, c), (c_plus, d), (d_plus, a_minus)];
+                print!("normalized:");
+                for (k, &(i_no, j_no, diff)) in legs.iter().enumerate() {
+                    let text = format!("{}->{}", edge_name(i_no), edge_name(j_no));
+                    hud.labels.push((100 * k, 0, text));
+                    hud.labels.push((100 * k, 20, format!("{}", diff)));
+
+                    let (i, j) = leg_indices[k];
+                    let similarity = *similarities.entry((i, j))
+                        .or_insert_with(|| edge_similarity(&edges, i, j));
+                    let text = format!("rms {:.2} {:.0}%/{:.0}%",
+                                       similarity.rms,
+                                       100.0 * similarity.coverage_a,
+                                       100.0 * similarity.coverage_b);
+                    print!(" {}", text);
+                    hud.labels.push((100 * k, 40, text));
+                }
+                println!();
+                if !edges[a].best_diff.is_empty() {
+                    let confidence = edge_confidence(&edges, a);
+                    println!("{} confidence {:.2}", edge_name(a_no), confidence);
+                    hud.lines.push(format!("{} best candidate confidence {:.2}",
+                                           edge_name(a_no),
+                                           confidence));
+                }
+                hud.labels.push((max_a.0, max_height + max_a.1, format!("A {}", edge_name(a_no))));
+                hud.labels.push((0, max_height + max_a.1, format!("B {}", edge_name(b_no))));
+                hud.labels.push((0, max_height, format!("C {}", edge_name(c_no))));
+                hud.labels.push((max_a.0, max_height, format!("D {}", edge_name(d_no))));
+
+                // Remember where pieces are so that user can pair edges with mouse
+                let drawn_pieces = vec![DrawnPiece {
+                                            piece_no: a_no >> 2,
+                                            rotation: a_no & 3,
+                                            left: max_a.0,
+                                            top: max_height + max_a.1,
+                                            points: piece_a,
+                                        },
+                                        DrawnPiece {
+                                            piece_no: b_no >> 2,
+                                            rotation: (b_no + 2) & 3,
+                                            left: 0,
+                                            top: max_height + max_a.1,
+                                            points: piece_b,
+                                        },
+                                        DrawnPiece {
+                                            piece_no: c_no >> 2,
+                                            rotation: (c_no + 3) & 3,
+                                            left: 0,
+                                            top: max_height,
+                                            points: piece_c,
+                                        },
+                                        DrawnPiece {
+                                            piece_no: d_no >> 2,
+                                            rotation: d_no & 3,
+                                            left: max_a.0,
+                                            top: max_height,
+                                            points: piece_d,
+                                        }];
+
                 // Go on if all 4edges solved
                 if final_score == 0 {
                     break 'combi_loop;
                 }
 
-                // Content for solved_edges.txt
-                let solved_str = {
-                    if display_state.autorotate {
-                        "".to_string()
-                    } else {
-                        let solved_tmp = format!("{}.{},{}.{}\n{}.{},{}.{}\n{}.{},{}.{}\n{}.{},\
-                                                  {}.{}\n",
-                                                 a_no >> 2,
-                                                 a_no & 3,
-                                                 b_no >> 2,
-                                                 b_no & 3,
-                                                 b_plus_no >> 2,
-                                                 b_plus_no & 3,
-                                                 c_no >> 2,
-                                                 c_no & 3,
-                                                 c_plus_no >> 2,
-                                                 c_plus_no & 3,
-                                                 d_no >> 2,
-                                                 d_no & 3,
-                                                 a_minus_no >> 2,
-                                                 a_minus_no & 3,
-                                                 d_plus_no >> 2,
-                                                 d_plus_no & 3);
-                        println!("\n{}", solved_tmp);
-                        solved_tmp
-                    }
+                // Pairs for solved_edges.txt, skip the already solved so that undo keeps them
+                let quad_pairs = [(a, b), (b_plus, c), (c_plus, d), (a_minus, d_plus)];
+                let mut solved_step = SolvedStep {
+                    pairs: vec![],
+                    score: final_score,
+                    source: "manual",
+                    timestamp: 0,
+                    session,
+                    lines: String::new(),
                 };
+                for &(i, j) in quad_pairs.iter() {
+                    if edges[i].solved_index != j {
+                        solved_step.pairs.push((i, j));
+                    }
+                }
+                if !display_state.autorotate {
+                    println!("\n{}", solved_lines(&edges, &solved_step));
+                }
+
+                // Accept clear match without asking, single quad has no margin to check
+                if auto_solve && best_round && final_score <= auto_max_score &&
+                   top_quads.len() >= 2 &&
+                   second_final_score as f64 >= auto_min_margin * final_score as f64 {
+                    println!("AUTO ACCEPTED score={} second best={}",
+                             final_score,
+                             second_final_score);
+                    solved_step.source = "auto";
+                    solved_step.timestamp = unix_time();
+                    solved_step.lines = solved_lines(&edges, &solved_step);
+                    append_to_file(solved_file, &solved_step.lines);
+                    write_history(&history_file, "auto", &edges, &solved_step);
+                    apply_solved(&mut edges, &solved_step);
+                    undo_stack.push(solved_step);
+                    redo_stack.clear();
+                    if frontier_mode {
+                        break 'pref_indices_loop;
+                    }
+                    continue 'combi_loop;
+                }
+                if auto_solve && best_round && top_quads.len() < 2 {
+                    println!("AMBIGUOUS score={} is the only quad, waiting for user",
+                             final_score);
+                } else if auto_solve && best_round {
+                    println!("AMBIGUOUS score={} second best={}, waiting for user",
+                             final_score,
+                             second_final_score);
+                }
+
+                // Manual pairing state is shown below the quad help lines, clicked edges
+                // are marked in copy of pixels
+                let hud_base_len = hud.lines.len();
+                let mut marked_pixels: Option<Vec<u8>> = None;
+                if let Some(first) = manual_first {
+                    hud.lines.push(format!("selected {}, click edge of other piece  N cancel",
+                                           edge_name(edges[first].edge_no)));
+                }
 
                 // Display result and use time for user key to compute diffs
                 'display_and_precompute: loop {
@@ -2007,11 +5470,13 @@ fn main() {
                     // autorotate=true will not wait for key
                     let autorotate_save = display_state.autorotate;
                     display_state.autorotate = true;
-                    let display_res = display_pixels(&pixels,
+                    let display_res = display_pixels(marked_pixels.as_ref().unwrap_or(&pixels),
                                                      sqr,
-                                                     &sdl_context,
+                                                     sdl_context,
                                                      &mut renderer,
-                                                     &mut display_state);
+                                                     display_state,
+                                                     &mut hud,
+                                                     font);
 
 
                     if autorotate_save {
@@ -2021,40 +5486,323 @@ fn main() {
 
                     match display_res {
                         UserAction::Solve => {
-                            let mut file = OpenOptions::new()
-                                .write(true)
-                                .append(true)
-                                .open("solved_edges.txt")
-                                .unwrap();
-
-                            if let Err(e) = file.write_all(solved_str.as_bytes()) {
-                                println!("{}", e);
+                            solved_step.timestamp = unix_time();
+                            solved_step.lines = solved_lines(&edges, &solved_step);
+                            append_to_file(solved_file, &solved_step.lines);
+                            write_history(&history_file, "solve", &edges, &solved_step);
+                            apply_solved(&mut edges, &solved_step);
+                            undo_stack.push(solved_step);
+                            redo_stack.clear();
+                            if frontier_mode {
+                                break 'pref_indices_loop;
+                            }
+                            break;
+                        }
+                        UserAction::Click(x, y) => {
+                            let i_no = match clicked_edge(&drawn_pieces, x, y) {
+                                Some(i_no) => i_no,
+                                None => continue 'display_and_precompute,
+                            };
+                            let i = match edge_nums.get(&i_no) {
+                                Some(i) => *i,
+                                None => {
+                                    println!("{} not found", edge_name(i_no));
+                                    continue 'display_and_precompute;
+                                }
+                            };
+                            if edges[i].solved_index != usize::max_value() {
+                                println!("{} is already solved to {}",
+                                         edge_name(i_no),
+                                         edge_name(edges[edges[i].solved_index].edge_no));
+                                continue 'display_and_precompute;
+                            }
+                            let first = match manual_first.take() {
+                                Some(first) if edges[first].edge_no >> 2 != i_no >> 2 => first,
+                                _ => {
+                                    let text = format!("selected {}, click edge of other piece  \
+                                                        N cancel",
+                                                       edge_name(i_no));
+                                    println!("{}", text);
+                                    hud.lines.truncate(hud_base_len);
+                                    hud.lines.push(text);
+                                    let mut marked = pixels.clone();
+                                    draw_mark(&mut marked, sqr, x, y, 255, 0, 255);
+                                    marked_pixels = Some(marked);
+                                    manual_first = Some(i);
+                                    manual_pair = None;
+                                    continue 'display_and_precompute;
+                                }
+                            };
+                            let diff = compare_edges(&edges, first, i);
+                            let text = format!("{}->{} diff {}  Y accept  N cancel",
+                                               edge_name(edges[first].edge_no),
+                                               edge_name(i_no),
+                                               diff);
+                            println!("{}", text);
+                            hud.lines.truncate(hud_base_len);
+                            hud.lines.push(text);
+                            let mut marked = marked_pixels.take().unwrap_or(pixels.clone());
+                            draw_mark(&mut marked, sqr, x, y, 255, 0, 255);
+                            marked_pixels = Some(marked);
+                            manual_pair = Some((first, i, diff));
+                        }
+                        UserAction::Confirm => {
+                            let (i, j, diff) = match manual_pair.take() {
+                                Some(pair) => pair,
+                                None => continue 'display_and_precompute,
+                            };
+                            let mut step = SolvedStep {
+                                pairs: vec![(i, j)],
+                                score: diff,
+                                source: "manual",
+                                timestamp: unix_time(),
+                                session,
+                                lines: String::new(),
+                            };
+                            step.lines = solved_lines(&edges, &step);
+                            append_to_file(solved_file, &step.lines);
+                            write_history(&history_file, "pair", &edges, &step);
+                            apply_solved(&mut edges, &step);
+                            undo_stack.push(step);
+                            redo_stack.clear();
+
+                            // Continue from the paired edge
+                            pref_new.clear();
+                            pref_new.push(i);
+                            break 'pref_indices_loop;
+                        }
+                        UserAction::Cancel => {
+                            println!("manual pairing canceled");
+                            manual_first = None;
+                            manual_pair = None;
+                            hud.lines.truncate(hud_base_len);
+                            marked_pixels = None;
+                        }
+                        UserAction::Assembled => {
+                            let (placements, _) = place_pieces(&edges, &edge_nums);
+                            let (assembled, assembled_sqr, labels) =
+                                draw_assembled(&edges, &edge_nums, &pieces, &placements, None);
+                            let mut hud = Hud {
+                                labels,
+                                lines: vec![format!("{} pieces placed, yellow edges are unsolved",
+                                                    placements.len()),
+                                            "arrows/P/M move and zoom  V or Esc back".to_string()],
+                                textures: None,
+                            };
+
+                            // Wait for keys until user goes back
+                            let autorotate_save = display_state.autorotate;
+                            display_state.autorotate = false;
+                            loop {
+                                match display_pixels(&assembled,
+                                                     assembled_sqr,
+                                                     sdl_context,
+                                                     &mut renderer,
+                                                     display_state,
+                                                     &mut hud,
+                                                     font) {
+                                    UserAction::Assembled |
+                                    UserAction::Quit => break,
+                                    _ => {}
+                                }
+                            }
+                            display_state.autorotate = autorotate_save;
+                        }
+                        UserAction::Block(num) => {
+                            // 2 for 2x3 block with A in the middle of right column, otherwise
+                            // 3x3 with A in the center. A is rotated as in quad view.
+                            let (start, size) = if num == 2 {
+                                ((1, 1), (2, 3))
                             } else {
-                                println!("written to solved_edges.txt");
+                                ((1, 1), (3, 3))
+                            };
+                            let params = BlockParams {
+                                start,
+                                size,
+                                num_candidates: block_candidates,
+                                beam: block_beam,
+                            };
+                            let blocks = match_block(&mut edges,
+                                                     &edge_nums,
+                                                     a_no >> 2,
+                                                     a_no & 3,
+                                                     &params,
+                                                     max_width,
+                                                     max_height);
+                            if blocks.is_empty() {
+                                println!("no {}x{} block found for {}", size.0, size.1, a_no >> 2);
+                                continue 'display_and_precompute;
                             }
-                            edges[a].solved_index = b;
-                            edges[b].solved_index = a;
-
-                            edges[b_plus].solved_index = c;
-                            edges[c].solved_index = b_plus;
+                            for (k, block) in blocks.iter().take(num_top_quads).enumerate() {
+                                println!("block {:>2} score {:>12}", k + 1, block.score);
+                            }
+                            let block = &blocks[0];
+
+                            // Colours as in quad view: A red, B on the left green, C above B
+                            // blue, D above A yellow, other pieces in order of placing
+                            let roles = [((0, 0), (255, 0, 0)),
+                                         ((-1, 0), (0, 255, 0)),
+                                         ((-1, -1), (0, 0, 255)),
+                                         ((0, -1), (255, 255, 0))];
+                            let others = [(255, 0, 255), (255, 128, 0), (128, 0, 255),
+                                          (0, 128, 128), (255, 128, 128)];
+                            let mut colors = HashMap::new();
+                            for (piece_no, p) in block.placements.iter() {
+                                let col = match roles.iter().find(|r| {
+                                    (r.0).0 == p.x - start.0 && (r.0).1 == p.y - start.1
+                                }) {
+                                    Some(role) => role.1,
+                                    None => others[p.cluster % others.len()],
+                                };
+                                colors.insert(*piece_no, piece_col(&edges, *piece_no, col.0,
+                                                                   col.1, col.2));
+                            }
+                            let mut placements = block.placements.clone();
+                            for p in placements.values_mut() {
+                                p.cluster = 0;
+                            }
+                            let (block_pixels, block_sqr, labels) =
+                                draw_assembled(&edges, &edge_nums, &pieces, &placements,
+                                               Some(&colors));
+                            let mut hud = Hud {
+                                labels,
+                                lines: vec![format!("{}x{} block around {} score={} \
+                                                     ({} found)",
+                                                    size.0,
+                                                    size.1,
+                                                    a_no >> 2,
+                                                    block.score,
+                                                    blocks.len())],
+                                textures: None,
+                            };
+                            let mut pair_line = String::new();
+                            for &(i, j, diff) in block.pairs.iter() {
+                                let pair = format!("{}->{} {}  ",
+                                                   edge_name(edges[i].edge_no),
+                                                   edge_name(edges[j].edge_no),
+                                                   diff);
+                                println!("{}", pair);
+                                if pair_line.len() + pair.len() > 100 {
+                                    hud.lines.push(pair_line);
+                                    pair_line = String::new();
+                                }
+                                pair_line += &pair;
+                            }
+                            hud.lines.push(pair_line);
+                            hud.lines.push("arrows/P/M move and zoom  B or Esc back".to_string());
+
+                            // Wait for keys until user goes back
+                            let autorotate_save = display_state.autorotate;
+                            display_state.autorotate = false;
+                            loop {
+                                match display_pixels(&block_pixels,
+                                                     block_sqr,
+                                                     sdl_context,
+                                                     &mut renderer,
+                                                     display_state,
+                                                     &mut hud,
+                                                     font) {
+                                    UserAction::Block(_) |
+                                    UserAction::Quit => break,
+                                    _ => {}
+                                }
+                            }
+                            display_state.autorotate = autorotate_save;
+                        }
+                        UserAction::Reject(num) => {
+                            // Typed number selects pair 1:A->B 2:B->C 3:C->D 4:D->A
+                            if !(1..=4).contains(&num) {
+                                println!("type pair number 1-4 before X, got {}", num);
+                                continue 'display_and_precompute;
+                            }
+                            let (i, j) = quad_pairs[num - 1];
+                            if edges[i].solved_index == j {
+                                println!("{}->{} is solved, undo it with U instead",
+                                         edge_name(edges[i].edge_no),
+                                         edge_name(edges[j].edge_no));
+                                continue 'display_and_precompute;
+                            }
+                            let rejected_str = format!("{},{}\n",
+                                                       edge_name(edges[i].edge_no),
+                                                       edge_name(edges[j].edge_no));
+                            print!("rejected {}", rejected_str);
+                            append_to_file(&rejected_file, &rejected_str);
+                            edges[i].rejected.push(j);
+                            edges[j].rejected.push(i);
+
+                            // Find the best match again without rejected pair
+                            pref_new.clear();
+                            pref_new.push(a);
+                            break 'pref_indices_loop;
+                        }
+                        UserAction::Undo => {
+                            let step = match undo_stack.pop() {
+                                Some(step) => step,
+                                None => {
+                                    println!("nothing to undo");
+                                    continue 'display_and_precompute;
+                                }
+                            };
+                            remove_solved_lines(solved_file, &step.lines);
+                            write_history(&history_file, "undo", &edges, &step);
+                            unapply_solved(&mut edges, &step);
 
-                            edges[c_plus].solved_index = d;
-                            edges[d].solved_index = c_plus;
+                            // Show the undone quad again
+                            pref_new.clear();
+                            pref_new.push(step.pairs[0].0);
+                            redo_stack.push(step);
+                            break 'pref_indices_loop;
+                        }
+                        UserAction::Redo => {
+                            let step = match redo_stack.pop() {
+                                Some(step) => step,
+                                None => {
+                                    println!("nothing to redo");
+                                    continue 'display_and_precompute;
+                                }
+                            };
+                            append_to_file(solved_file, &step.lines);
+                            write_history(&history_file, "redo", &edges, &step);
+                            apply_solved(&mut edges, &step);
 
-                            edges[d_plus].solved_index = a_minus;
-                            edges[a_minus].solved_index = d_plus;
-                            break;
+                            pref_new.clear();
+                            pref_new.push(step.pairs[0].0);
+                            undo_stack.push(step);
+                            break 'pref_indices_loop;
                         }
                         UserAction::Delete => {
-                            println!("{:?}",
-                                     fs::remove_file(format!("data/{}.png.done", a_no >> 2)));
-                            println!("{:?}",
-                                     fs::remove_file(format!("data/{}.png.done", b_no >> 2)));
-                            /*println!("{:?}",
-                                     fs::remove_file(format!("data/{}.png.done", c_no >> 2)));
-                            println!("{:?}",
-                                     fs::remove_file(format!("data/{}.png.done", d_no >> 2)));*/
-                            break;
+                            // Extract pieces A and B again and continue with new edges
+                            for piece_no in [a_no >> 2, b_no >> 2].iter() {
+                                let png_file = format!("{}/{}.png", data_dir, piece_no);
+                                println!("{:?}", fs::remove_file(format!("{}.done", png_file)));
+                                if !Path::new(&png_file).exists() {
+                                    println!("{} not found", png_file);
+                                    continue;
+                                }
+                                process_png(&png_file,
+                                            *piece_no,
+                                            sdl_context,
+                                            display_state,
+                                            font,
+                                            &extraction_params(config, *piece_no),
+                                            config);
+                                similarities.clear();
+                                if !reload_piece(&mut edges,
+                                                 &mut pieces,
+                                                 &edge_nums,
+                                                 data_dir,
+                                                 *piece_no,
+                                                 (max_width, max_height),
+                                                 config) {
+                                    // Solved edges are saved, nothing is lost by stopping
+                                    println!("solver stopped, start it again to continue");
+                                    return;
+                                }
+                            }
+                            display_state.autorotate = false;
+                            pref_new.clear();
+                            pref_new.push(a);
+                            break 'pref_indices_loop;
                         }
                         UserAction::Number(num) => {
                             pref_new.clear();
@@ -2071,6 +5819,9 @@ fn main() {
                         }
                         UserAction::Compute => {
                             combi_shift = (combi_shift + 1) % 4;
+                            if auto_solve && combi_shift == 0 {
+                                combi_shift = 1;
+                            }
                             println!("combi_shift={}", combi_shift);
                             pref_new.clear();
                             pref_new.push(a);
@@ -2080,14 +5831,15 @@ fn main() {
                         UserAction::NoAction => {
                             // Compare edges while waiting for key
                             for i in 0..edges_len {
-                                if edges[i].diff_to.len() != 0 ||
+                                let num_best = cmp::max(counts.0, cmp::max(counts.1, counts.2));
+                                if edges[i].best_diff.len() >= num_best ||
                                    edges[i].solved_index != usize::max_value() {
                                     continue;
                                 }
                                 //println!("comparing {}/{}", i, edges_len);
                                 compute_best_diff(i,
                                                   &mut edges,
-                                                  combi_one_edge,
+                                                  num_best,
                                                   max_width,
                                                   max_height);
                                 break;
@@ -2102,3 +5854,239 @@ fn main() {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scale_key_uses_file_name() {
+        assert_eq!(scale_key("jpg/12.jpg"), "scale.12.jpg");
+        assert_eq!(scale_key("12.jpg"), "scale.12.jpg");
+    }
+
+    fn assert_near(a: (f64, f64), b: (f64, f64), tolerance: f64) {
+        assert!(distance(a, b) <= tolerance, "{:?} != {:?}", a, b);
+    }
+
+    #[test]
+    fn homography_maps_reference_points() {
+        let src = [(10.0, 20.0), (410.0, 5.0), (430.0, 320.0), (0.0, 300.0)];
+        let dst = [(0.0, 0.0), (400.0, 0.0), (400.0, 300.0), (0.0, 300.0)];
+        let h = homography(&src, &dst).unwrap();
+        for k in 0..4 {
+            assert_near(apply_homography(&h, src[k].0, src[k].1), dst[k], 1e-6);
+        }
+
+        // Points between reference points stay inside rectangle
+        let (x, y) = apply_homography(&h, 215.0, 160.0);
+        assert!(x > 0.0 && x < 400.0 && y > 0.0 && y < 300.0);
+    }
+
+    #[test]
+    fn homography_of_the_same_points_is_identity() {
+        let points = [(0.0, 0.0), (100.0, 0.0), (100.0, 50.0), (0.0, 50.0)];
+        let h = homography(&points, &points).unwrap();
+        assert_near(apply_homography(&h, 37.0, 12.5), (37.0, 12.5), 1e-6);
+    }
+
+    #[test]
+    fn homography_of_points_on_one_line_is_none() {
+        let dst = [(0.0, 0.0), (100.0, 0.0), (100.0, 50.0), (0.0, 50.0)];
+        let line = [(0.0, 0.0), (10.0, 10.0), (20.0, 20.0), (0.0, 40.0)];
+        assert!(homography(&line, &dst).is_none());
+        let double_click = [(0.0, 0.0), (0.0, 0.0), (100.0, 50.0), (0.0, 50.0)];
+        assert!(homography(&double_click, &dst).is_none());
+    }
+
+    #[test]
+    fn parse_args_splits_command_params_and_flags() {
+        let args: Vec<String> = ["solve", "12", "--frontier", "--data=pieces", "--solved", "s.txt"]
+            .iter()
+            .map(|a| a.to_string())
+            .collect();
+        let mut config = HashMap::new();
+        let (command, params, flags) = parse_args(&args, &mut config);
+        assert_eq!(command, "solve");
+        assert_eq!(params, vec!["12".to_string()]);
+        assert_eq!(flags, vec!["frontier".to_string()]);
+        assert_eq!(config["data_dir"], "pieces");
+        assert_eq!(config["solved_file"], "s.txt");
+    }
+
+    #[test]
+    fn parse_args_puts_per_piece_keys_to_config() {
+        let args: Vec<String> = ["extract", "--jag-width.12", "8", "--top-quads=3"]
+            .iter()
+            .map(|a| a.to_string())
+            .collect();
+        let mut config = HashMap::new();
+        let (command, params, _) = parse_args(&args, &mut config);
+        assert_eq!(command, "extract");
+        assert!(params.is_empty());
+        assert_eq!(config["jag_width.12"], "8");
+        assert_eq!(config["top_quads"], "3");
+    }
+
+    #[test]
+    fn unrotate_point_reverts_rotate_point() {
+        let (width, height) = (300, 200);
+        let (shift, _) = rotation_square(width as u32, height as u32);
+        for &angle in [0.0, 3.5, 90.0, 181.0, 268.2].iter() {
+            for &p in [(0.0, 0.0), (150.0, 100.0), (299.0, 17.0), (12.0, 199.0)].iter() {
+                let r = rotate_point(p.0, p.1, angle, shift, width, height);
+                let u = unrotate_point(r.0.round() as usize,
+                                       r.1.round() as usize,
+                                       angle,
+                                       shift,
+                                       width,
+                                       height);
+                // Rotated point is rounded to pixel
+                assert_near(u, p, 1.0);
+            }
+        }
+    }
+
+    // Four edges without points for each piece, edge_nums maps edge_no to index
+    fn test_edges(piece_nos: &[usize]) -> (Vec<EdgeInfo>, HashMap<usize, usize>) {
+        let mut edges = vec![];
+        let mut edge_nums = HashMap::new();
+        for &piece_no in piece_nos.iter() {
+            for side in 0..4 {
+                edge_nums.insert(4 * piece_no + side, edges.len());
+                edges.push(EdgeInfo {
+                    points: vec![],
+                    edge_no: 4 * piece_no + side,
+                    edge_index: edges.len(),
+                    max_x: 0,
+                    max_y: 0,
+                    diff_to: vec![],
+                    best_diff: vec![],
+                    solved_index: usize::max_value(),
+                    rejected: vec![],
+                    candidates: vec![],
+                    solved_source: String::new(),
+                });
+            }
+        }
+        (edges, edge_nums)
+    }
+
+    // (piece, side) of both edges
+    type SidePair = ((usize, usize), (usize, usize));
+
+    fn solve_pairs(edges: &mut Vec<EdgeInfo>,
+                   edge_nums: &HashMap<usize, usize>,
+                   pairs: &[SidePair]) {
+        for &(a, b) in pairs.iter() {
+            let i = edge_nums[&(4 * a.0 + a.1)];
+            let j = edge_nums[&(4 * b.0 + b.1)];
+            edges[i].solved_index = j;
+            edges[j].solved_index = i;
+        }
+    }
+
+    // Closed 2x2 block, pieces 3 4 above 2 1, all with side 0 on the left
+    const CLOSED_BLOCK: [SidePair; 4] = [((1, 0), (2, 2)),
+                                         ((2, 3), (3, 1)),
+                                         ((3, 2), (4, 0)),
+                                         ((4, 1), (1, 3))];
+
+    #[test]
+    fn place_pieces_of_closed_block() {
+        let (mut edges, edge_nums) = test_edges(&[1, 2, 3, 4]);
+        solve_pairs(&mut edges, &edge_nums, &CLOSED_BLOCK);
+        let (placements, contradictions) = place_pieces(&edges, &edge_nums);
+        assert!(contradictions.is_empty(), "{:?}", contradictions);
+        let cell = |piece_no: usize| {
+            let p = placements[&piece_no];
+            (p.x, p.y, p.rotation, p.cluster)
+        };
+        assert_eq!(cell(1), (0, 0, 0, 0));
+        assert_eq!(cell(2), (-1, 0, 0, 0));
+        assert_eq!(cell(3), (-1, -1, 0, 0));
+        assert_eq!(cell(4), (0, -1, 0, 0));
+    }
+
+    #[test]
+    fn place_pieces_reports_same_cell() {
+        // Piece 5 right of piece 3 lands on cell of piece 4
+        let (mut edges, edge_nums) = test_edges(&[1, 2, 3, 4, 5]);
+        solve_pairs(&mut edges,
+                    &edge_nums,
+                    &[((1, 0), (2, 2)), ((2, 3), (3, 1)), ((1, 3), (4, 1)), ((3, 2), (5, 0))]);
+        let (placements, contradictions) = place_pieces(&edges, &edge_nums);
+        assert_eq!(placements.len(), 5);
+        assert_eq!(contradictions.len(), 1);
+        assert!(contradictions[0].contains("claim the same cell 0,-1"), "{}", contradictions[0]);
+    }
+
+    #[test]
+    fn check_cycles_counts_closed_block() {
+        let (mut edges, edge_nums) = test_edges(&[1, 2, 3, 4]);
+        solve_pairs(&mut edges, &edge_nums, &CLOSED_BLOCK);
+        let (closed, violations) = check_cycles(&edges, &edge_nums);
+        assert_eq!(closed, 1);
+        assert!(violations.is_empty());
+    }
+
+    #[test]
+    fn check_cycles_reports_block_not_closing() {
+        // Last pair goes to piece 5 instead of back to piece 1
+        let (mut edges, edge_nums) = test_edges(&[1, 2, 3, 4, 5]);
+        solve_pairs(&mut edges,
+                    &edge_nums,
+                    &[((1, 0), (2, 2)), ((2, 3), (3, 1)), ((3, 2), (4, 0)), ((4, 1), (5, 3))]);
+        let (closed, violations) = check_cycles(&edges, &edge_nums);
+        assert_eq!(closed, 0);
+        assert_eq!(violations, vec![vec![(4, 10), (11, 13), (14, 16), (17, 23)]]);
+    }
+
+    #[test]
+    fn kd_nearest_finds_the_same_as_brute_force() {
+        // Pseudo random descriptors, the same for each run
+        let mut seed = 12345u64;
+        let mut random = || {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (seed >> 33) as f64 / (1u64 << 31) as f64
+        };
+        let descriptors: Vec<Vec<f64>> = (0..200)
+            .map(|_| (0..DESCRIPTOR_LEN).map(|_| 100.0 * random()).collect())
+            .collect();
+        let mut indices: Vec<usize> = (0..descriptors.len()).collect();
+        let mut nodes = vec![];
+        let root = build_kd_tree(&descriptors, &mut indices, 0, &mut nodes);
+        assert_eq!(nodes.len(), descriptors.len());
+
+        for _ in 0..20 {
+            let query: Vec<f64> = (0..DESCRIPTOR_LEN).map(|_| 100.0 * random()).collect();
+            let mut best = vec![];
+            kd_nearest(&nodes, &descriptors, root, &query, 7, &mut best);
+
+            let mut all: Vec<(f64, usize)> = descriptors.iter()
+                .enumerate()
+                .map(|(i, d)| (descriptor_dst(&query, d), i))
+                .collect();
+            all.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+            all.truncate(7);
+            assert_eq!(best, all);
+        }
+    }
+
+    #[test]
+    fn index_candidates_leaves_out_own_piece() {
+        let (mut edges, _) = test_edges(&[1, 2, 3]);
+        for (i, edge) in edges.iter_mut().enumerate() {
+            edge.points = (0..64).map(|y| (i + y % 3, y)).collect();
+            edge.max_x = max_xy(&edge.points).0;
+            edge.max_y = 63;
+        }
+        index_candidates(&mut edges, 3);
+        for edge in edges.iter() {
+            assert_eq!(edge.candidates.len(), 3);
+            for &j in edge.candidates.iter() {
+                assert!(edges[j].edge_no >> 2 != edge.edge_no >> 2);
+            }
+        }
+    }
+}